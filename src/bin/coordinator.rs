@@ -1,8 +1,15 @@
+use clap::Parser;
+use rustkv::client::Client;
+use rustkv::protocol::Protocol;
+use rustkv::{Command, NamespaceAllocation, Node, ResponseBody};
+use std::collections::HashMap;
+use std::process;
 use std::sync::mpsc::channel;
-use std::thread;
 use std::time::Duration;
 use zookeeper::AddWatchMode;
-use zookeeper::{Acl, CreateMode, WatchedEvent, Watcher, ZooKeeper};
+use zookeeper::{
+    Acl, CreateMode, WatchedEvent, WatchedEventType, Watcher, ZkError, ZkState, ZooKeeper,
+};
 
 struct LoggingWatcher;
 impl Watcher for LoggingWatcher {
@@ -27,7 +34,8 @@ impl Watcher for LoggingWatcher {
 *
 *
 * **Assumptions**:
-*   - no two nodes will try to manage the same range or overlapping ranges.
+*   - no two nodes will try to manage the same range or overlapping ranges. If they do anyway, the
+*   range that starts later is trimmed to start after the other one.
 *   - the node has already created a node in ZK under `/nodes`. The full path will be
 *   `/nodes/node-{node_id}`. The value of `node_id` is included in the registration request.
 *
@@ -38,6 +46,9 @@ impl Watcher for LoggingWatcher {
 * if a node was already replicating data previously and keep the same assignment. When the node
 * registers it indicates if it replicating a range previously and which one.
 *
+* The allocations, with the replicas of each range, are published to `/allocations` for the nodes
+* and the clients to read.
+*
 * ## Node disconnection
 *
 * The coordinator watches the ZK node `/nodes/node-{node_id}`. If the node is deleted the
@@ -48,86 +59,598 @@ impl Watcher for LoggingWatcher {
 *
 * When the system switches to read-only mode the coordinator also changes the allocations. It
 * will update the allocations to assign ownership of the range belonging to the disconnected node
-* to one of its replicas. Note that in a real life scenario this could lead to an increase in load
-* in the replica that could trigger a cascading failure. A possible option would be to let clients
-* round robing between the replicas.
+* to one of its replicas. The coordinator asks the replicas how much of the log of the node they
+* applied and picks the one that got the furthest. Note that in a real life scenario this could
+* lead to an increase in load in the replica that could trigger a cascading failure. A possible
+* option would be to let clients round robing between the replicas.
+*
+* ## Coordinator election
+*
+* Several coordinators can run at once, but only one of them leads. Each one creates an ephemeral
+* sequential ZK node under `/coordinators` and the one with the lowest sequence is the leader. The
+* others wait for the node right before theirs to be deleted, so when the leader dies only the next
+* one in line wakes up. A new leader picks up the allocations published to `/allocations` by the
+* previous one and the nodes registered under `/nodes`, so it fails over the nodes that went away
+* in the meantime instead of allocating the ranges again.
+*
+* A leader that loses its session, or its node under `/coordinators`, exits. It only replaces the
+* version of `/allocations` it read or wrote last, so a leader that doesn't notice it was deposed
+* can't overwrite what the new one published.
 * */
 
+#[derive(Parser)]
+struct Args {
+    // How many nodes have to register before the ranges are allocated
+    #[arg(long)]
+    nodes: usize,
+
+    // How many other nodes keep a copy of each range, every other node if it's not set
+    #[arg(long)]
+    replicas: Option<usize>,
+}
+
+// Nodes registered under `/nodes`, a node that goes away while they are read is left out
+fn registered_nodes(zk: &ZooKeeper) -> Vec<Node> {
+    let mut nodes = Vec::new();
+
+    for child in zk.get_children("/nodes", false).unwrap() {
+        let binary = match zk.get_data(&format!("/nodes/{child}"), false) {
+            Ok((binary, _)) => binary,
+            Err(ZkError::NoNode) => continue,
+            Err(e) => panic!("Unexpected error {:?}", e),
+        };
+
+        match bincode::deserialize::<Node>(&binary) {
+            Ok(node) => nodes.push(node),
+            Err(e) => println!("Ignoring node {}: {}", child, e),
+        }
+    }
+
+    nodes.sort_by_key(|node| node.node_id);
+    nodes
+}
+
+/*
+ * Gives every node the range it proposed, in the order of the ranges. A range that overlaps the
+ * ones before it is trimmed to start after them, and the node owns nothing if nothing is left.
+ *
+ * Each range gets `replicas` other nodes to replicate it: first the nodes that were replicating it
+ * already, then the ones replicating the fewest ranges.
+ */
+fn allocate(nodes: &[Node], replicas: usize) -> Vec<NamespaceAllocation> {
+    let mut proposals: Vec<&Node> = nodes.iter().collect();
+    proposals.sort_by_key(|node| (*node.range.start(), node.node_id));
+
+    let mut allocations: Vec<NamespaceAllocation> = Vec::new();
+    for node in proposals {
+        let start = match allocations.last() {
+            Some(previous) if previous.range.end() >= node.range.start() => {
+                match char::from_u32(*previous.range.end() as u32 + 1) {
+                    Some(start) => start,
+                    None => continue,
+                }
+            }
+            _ => *node.range.start(),
+        };
+
+        if start > *node.range.end() {
+            println!("Node {} doesn't own any range", node.address);
+            continue;
+        }
+
+        allocations.push(NamespaceAllocation {
+            node: node.address.clone(),
+            range: start..=*node.range.end(),
+            replicas: Vec::new(),
+        });
+    }
+
+    let mut replicating: HashMap<&str, usize> = HashMap::new();
+    for allocation in &mut allocations {
+        let mut candidates: Vec<&Node> = nodes
+            .iter()
+            .filter(|node| node.address != allocation.node)
+            .collect();
+        candidates.sort_by_key(|node| {
+            (
+                !node.replicating.contains(&allocation.node),
+                replicating.get(node.address.as_str()).copied().unwrap_or(0),
+                node.node_id,
+            )
+        });
+
+        for node in candidates.into_iter().take(replicas) {
+            *replicating.entry(&node.address).or_default() += 1;
+            allocation.replicas.push(node.address.clone());
+        }
+    }
+
+    allocations
+}
+
+/*
+ * Hands the range of every owner that is gone to its replica that applied the most of its log.
+ * From then on the range is replicated along with the other ranges of the promoted node, to the
+ * replicas of the node. Nodes that are gone stop being replicas, and the owners left with fewer
+ * than `replicas` get the alive nodes replicating the fewest ranges instead.
+ *
+ * `sequence` tells how much of the log of an owner (second argument) a replica (first argument)
+ * has, `None` if it doesn't know.
+ */
+fn fail_over(
+    allocations: &[NamespaceAllocation],
+    alive: &[String],
+    replicas: usize,
+    sequence: impl Fn(&str, &str) -> Option<usize>,
+) -> Vec<NamespaceAllocation> {
+    let alive_replicas = |replicas: &[String], owner: &str| -> Vec<String> {
+        replicas
+            .iter()
+            .filter(|replica| alive.contains(replica) && *replica != owner)
+            .cloned()
+            .collect()
+    };
+
+    let mut failed_over: Vec<NamespaceAllocation> = allocations
+        .iter()
+        .map(|allocation| {
+            if alive.contains(&allocation.node) {
+                return NamespaceAllocation {
+                    replicas: alive_replicas(&allocation.replicas, &allocation.node),
+                    ..allocation.clone()
+                };
+            }
+
+            let promoted = allocation
+                .replicas
+                .iter()
+                .filter(|replica| alive.contains(replica))
+                .filter_map(|replica| {
+                    sequence(replica, &allocation.node).map(|sequence| (sequence, replica))
+                })
+                .max_by_key(|(sequence, _)| *sequence);
+
+            let Some((sequence, promoted)) = promoted else {
+                println!(
+                    "No replica can take over the range {:?} of {}",
+                    allocation.range, allocation.node
+                );
+                return allocation.clone();
+            };
+
+            println!(
+                "Promoting {} at sequence {} to owner of the range {:?} of {}",
+                promoted, sequence, allocation.range, allocation.node
+            );
+            let replicas = allocations
+                .iter()
+                .find(|other| &other.node == promoted)
+                .map_or(&allocation.replicas, |other| &other.replicas);
+
+            NamespaceAllocation {
+                node: promoted.clone(),
+                range: allocation.range.clone(),
+                replicas: alive_replicas(replicas, promoted),
+            }
+        })
+        .collect();
+
+    // Every range of an owner has the same replicas, they are replaced once per owner
+    let mut replicating: HashMap<String, usize> = HashMap::new();
+    for replica in failed_over
+        .iter()
+        .flat_map(|allocation| &allocation.replicas)
+    {
+        *replicating.entry(replica.clone()).or_default() += 1;
+    }
+    let mut replaced: HashMap<String, Vec<String>> = HashMap::new();
+    for allocation in &mut failed_over {
+        // The range of an owner that is gone waits for one of its replicas to take over
+        if !alive.contains(&allocation.node) || allocation.replicas.len() >= replicas {
+            continue;
+        }
+
+        if let Some(replicas) = replaced.get(&allocation.node) {
+            allocation.replicas = replicas.clone();
+            continue;
+        }
+
+        let mut candidates: Vec<&String> = alive
+            .iter()
+            .filter(|node| **node != allocation.node && !allocation.replicas.contains(node))
+            .collect();
+        candidates.sort_by_key(|node| (replicating.get(*node).copied().unwrap_or(0), *node));
+
+        let missing = replicas - allocation.replicas.len();
+        for node in candidates.into_iter().take(missing) {
+            println!("Replacing a replica of {} with {}", allocation.node, node);
+            *replicating.entry(node.clone()).or_default() += 1;
+            allocation.replicas.push(node.clone());
+        }
+        replaced.insert(allocation.node.clone(), allocation.replicas.clone());
+    }
+
+    failed_over
+}
+
+// How much of the log of `owner` the `replica` has applied. Replicas that don't answer in time
+// are not candidates.
+fn replicated_sequence(replica: &str, owner: &str) -> Option<usize> {
+    let timeout = Duration::from_millis(1000);
+    let mut client = Client::connect_timeout(replica, Protocol::Bincode, timeout)
+        .inspect_err(|e| println!("Can't reach {}: {}", replica, e))
+        .ok()?;
+    let response = client
+        .request(Command::ReplicatedSequence {
+            owner: owner.to_string(),
+        })
+        .inspect_err(|e| println!("No answer from {}: {}", replica, e))
+        .ok()?;
+
+    match response.body {
+        ResponseBody::ReplicatedSequence(sequence) => sequence,
+        body => {
+            println!("Unexpected response from {}: {:?}", replica, body);
+            None
+        }
+    }
+}
+
+fn create_persistent(zk: &ZooKeeper, path: &str) {
+    match zk.create(
+        path,
+        vec![],
+        Acl::open_unsafe().clone(),
+        CreateMode::Persistent,
+    ) {
+        Err(ZkError::NodeExists) => println!("Node {} exists", path),
+        Err(_) => panic!("Unexpected error"),
+        Ok(_) => (),
+    }
+}
+
+// Candidate right before `name` in line, `None` when `name` leads
+fn predecessor(candidates: &[String], name: &str) -> Option<String> {
+    // The sequences have the same number of digits, they sort as text
+    let mut candidates = candidates.to_vec();
+    candidates.sort();
+
+    let position = candidates
+        .iter()
+        .position(|candidate| candidate == name)
+        .unwrap();
+
+    position
+        .checked_sub(1)
+        .map(|previous| candidates[previous].clone())
+}
+
+// Blocks until this coordinator is the leader. Returns the path of its candidate node.
+fn elect(zk: &ZooKeeper) -> String {
+    let path = zk
+        .create(
+            "/coordinators/candidate-",
+            vec![],
+            Acl::open_unsafe().clone(),
+            CreateMode::EphemeralSequential,
+        )
+        .unwrap();
+    let name = path.trim_start_matches("/coordinators/").to_string();
+
+    loop {
+        let candidates = zk.get_children("/coordinators", false).unwrap();
+        let Some(previous) = predecessor(&candidates, &name) else {
+            println!("Leading as {}", name);
+            return path;
+        };
+
+        let (send, recv) = channel();
+        let watcher = move |_: WatchedEvent| {
+            let _ = send.send(());
+        };
+
+        // The previous candidate might be gone already
+        if zk
+            .exists_w(&format!("/coordinators/{previous}"), watcher)
+            .unwrap()
+            .is_some()
+        {
+            println!("Standing by behind {}", previous);
+            recv.recv().unwrap();
+        }
+    }
+}
+
+fn step_down(reason: &str) -> ! {
+    println!("Stepping down: {}", reason);
+    process::exit(1)
+}
+
+// Another leader wrote `/allocations` since this one read it
+fn deposed(error: &ZkError) -> bool {
+    matches!(error, ZkError::BadVersion | ZkError::NodeExists)
+}
+
+// Allocations published by a previous leader, if any, and the version of `/allocations`
+fn published_allocations(zk: &ZooKeeper) -> (Option<Vec<NamespaceAllocation>>, Option<i32>) {
+    match zk.get_data("/allocations", false) {
+        Ok((binary, stat)) => (bincode::deserialize(&binary).ok(), Some(stat.version)),
+        Err(ZkError::NoNode) => (None, None),
+        Err(e) => panic!("Unexpected error {:?}", e),
+    }
+}
+
+/*
+ * Replaces the `version` of `/allocations` the leader knows about, or creates it if there was none.
+ * Returns the new version.
+ */
+fn publish(zk: &ZooKeeper, allocations: &[NamespaceAllocation], version: Option<i32>) -> i32 {
+    let binary = bincode::serialize(allocations).unwrap();
+    let published = match version {
+        Some(version) => zk
+            .set_data("/allocations", binary, Some(version))
+            .map(|stat| stat.version),
+        // A new node starts at version 0
+        None => zk
+            .create(
+                "/allocations",
+                binary,
+                Acl::open_unsafe().clone(),
+                CreateMode::Persistent,
+            )
+            .map(|_| 0),
+    };
+
+    match published {
+        Ok(version) => version,
+        Err(e) if deposed(&e) => step_down("another leader published allocations"),
+        Err(e) => panic!("Unexpected error {:?}", e),
+    }
+}
+
 pub fn main() {
-    let zk = ZooKeeper::connect("localhost:2181", Duration::from_secs(1), LoggingWatcher).unwrap();
-    // TODO: handle coordinator restart when there are already nodes registered
+    let args = Args::parse();
+    let replicas = args.replicas.unwrap_or(args.nodes.saturating_sub(1));
+    // The session timeout is how long the standbys wait for a leader that died
+    let zk = ZooKeeper::connect("localhost:2181", Duration::from_secs(5), LoggingWatcher).unwrap();
     // NOTE: it seems possible that a node crashes and restarts quickly. On that case is also
     // possible that ZK will deliver first the creation of the ZK node before the deletion of the
     // previous one.
 
     env_logger::init();
 
+    create_persistent(&zk, "/nodes");
+    create_persistent(&zk, "/coordinators");
+    let candidate = elect(&zk);
+
+    // The ephemeral nodes of the session are gone, a standby leads already or soon will
+    zk.add_listener(|state| {
+        if state == ZkState::Closed {
+            step_down("the ZooKeeper session expired");
+        }
+    });
+    let lost = |event: WatchedEvent| {
+        if event.event_type == WatchedEventType::NodeDeleted {
+            step_down("the candidate node is gone");
+        }
+    };
+    if zk.exists_w(&candidate, lost).unwrap().is_none() {
+        step_down("the candidate node is gone");
+    }
+
     let (send, recv) = channel();
-    let closure = move |event: WatchedEvent| send.send(()).unwrap();
+    let closure = move |event: WatchedEvent| send.send(event).unwrap();
     zk.add_watch("/nodes", AddWatchMode::PersistentRecursive, closure)
         .unwrap();
 
-    let node_create = zk.create(
-        "/nodes",
-        vec![],
-        Acl::open_unsafe().clone(),
-        CreateMode::Persistent,
-    );
+    // The nodes that registered before the coordinator led are read before the first event.
+    // Allocations left by a previous run of the cluster are picked up too, delete `/allocations`
+    // to start over.
+    let (mut allocations, mut version) = published_allocations(&zk);
+    loop {
+        let nodes = registered_nodes(&zk);
+        println!("{}/{} nodes registered", nodes.len(), args.nodes);
 
-    match node_create {
-        Err(zookeeper::ZkError::NodeExists) => println!("Node exists"),
-        Err(_) => panic!("Unexpected error"),
-        Ok(_) => (),
+        match &allocations {
+            None if nodes.len() >= args.nodes => {
+                let allocated = allocate(&nodes, replicas);
+
+                println!("Allocations {:?}", allocated);
+                version = Some(publish(&zk, &allocated, version));
+                allocations = Some(allocated);
+            }
+            Some(current) => {
+                let alive: Vec<String> = nodes.into_iter().map(|node| node.address).collect();
+                let updated = fail_over(current, &alive, replicas, replicated_sequence);
+
+                if updated != *current {
+                    println!("Allocations {:?}", updated);
+                    version = Some(publish(&zk, &updated, version));
+                    allocations = Some(updated);
+                }
+            }
+            None => (),
+        }
+
+        let event = recv.recv().unwrap();
+        println!("Event {:?}, path {:?}", event.event_type, event.path);
     }
+}
 
-    for x in recv.iter() {
-        println!("Event {:?}, path {:?}", x, x);
-    }
-
-    // zk.get_children("/nodes", true).unwrap();
-
-    // let allocations_exist = zk.exists("/allocations", false);
-    //
-    // match allocations_exist {
-    //     Err(_) => panic!("Unexpected error"),
-    //     Ok(Some(_)) => (),
-    //     Ok(None) => {
-    //         println!("Create allocations");
-    //         let allocations = vec![
-    //             NamespaceAllocation {
-    //                 node: String::from_str("localhost:1337").unwrap(),
-    //                 range: 'a'..='h',
-    //             },
-    //             NamespaceAllocation {
-    //                 node: String::from_str("localhost:1338").unwrap(),
-    //                 range: 'i'..='q',
-    //             },
-    //             NamespaceAllocation {
-    //                 node: String::from_str("localhost:1339").unwrap(),
-    //                 range: 'r'..='z',
-    //             },
-    //         ];
-    //
-    //         println!("allocations raw {:?}", allocations);
-    //
-    //         println!(
-    //             "allocations {:?}",
-    //             bincode::deserialize::<Vec<NamespaceAllocation>>(
-    //                 &bincode::serialize(&allocations).unwrap()
-    //             )
-    //         );
-    //         zk.create(
-    //             "/allocations",
-    //             bincode::serialize(&allocations).unwrap(),
-    //             Acl::open_unsafe().clone(),
-    //             CreateMode::Persistent,
-    //         )
-    //         .unwrap();
-    //     }
-    // }
+#[cfg(test)]
+mod tests {
+    use super::{allocate, deposed, fail_over, predecessor, replicated_sequence};
+    use rustkv::{NamespaceAllocation, Node};
+    use std::net::TcpListener;
+    use std::ops::RangeInclusive;
+    use std::time::{Duration, Instant};
+    use zookeeper::ZkError;
 
-    loop {
-        thread::sleep(Duration::from_secs(1800));
-        println!("Awake");
+    fn node(node_id: u8, range: RangeInclusive<char>, replicating: &[&str]) -> Node {
+        Node {
+            node_id,
+            address: format!("localhost:{}", 1336 + node_id as u16),
+            range,
+            replicating: replicating.iter().map(|owner| owner.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_overlapping_ranges_are_trimmed() {
+        let nodes = vec![
+            node(1, 'i'..='q', &[]),
+            node(2, 'a'..='j', &[]),
+            node(3, 'b'..='h', &[]),
+        ];
+        let allocations = allocate(&nodes, 0);
+
+        let ranges: Vec<(&str, RangeInclusive<char>)> = allocations
+            .iter()
+            .map(|allocation| (allocation.node.as_str(), allocation.range.clone()))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![("localhost:1338", 'a'..='j'), ("localhost:1337", 'k'..='q')]
+        );
+    }
+
+    #[test]
+    fn test_replicas_are_kept_and_spread() {
+        let nodes = vec![
+            node(1, 'a'..='h', &[]),
+            node(2, 'i'..='q', &[]),
+            node(3, 'r'..='z', &["localhost:1337"]),
+        ];
+        let allocations = allocate(&nodes, 1);
+
+        let replicas: Vec<&[String]> = allocations
+            .iter()
+            .map(|allocation| allocation.replicas.as_slice())
+            .collect();
+        assert_eq!(
+            replicas,
+            vec![
+                &["localhost:1339".to_string()][..],
+                &["localhost:1337".to_string()][..],
+                &["localhost:1338".to_string()][..],
+            ]
+        );
+
+        let allocations = allocate(&nodes, 5);
+        assert!(allocations
+            .iter()
+            .all(|allocation| allocation.replicas.len() == 2));
+    }
+
+    #[test]
+    fn test_the_most_up_to_date_replica_takes_over() {
+        let allocation =
+            |node: &str, range: RangeInclusive<char>, replicas: &[&str]| NamespaceAllocation {
+                node: node.to_string(),
+                range,
+                replicas: replicas.iter().map(|replica| replica.to_string()).collect(),
+            };
+        let allocations = vec![
+            allocation("a", 'a'..='h', &["b", "c"]),
+            allocation("b", 'i'..='q', &["a", "c"]),
+            allocation("c", 'r'..='z', &["a"]),
+        ];
+        let sequences = |replica: &str, owner: &str| match (replica, owner) {
+            ("b", "a") => Some(10),
+            ("c", "a") => Some(12),
+            _ => None,
+        };
+
+        let alive = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(fail_over(&allocations, &alive, 1, sequences), allocations);
+
+        let alive = vec!["b".to_string(), "c".to_string()];
+        assert_eq!(
+            fail_over(&allocations, &alive, 0, sequences),
+            vec![
+                allocation("c", 'a'..='h', &[]),
+                allocation("b", 'i'..='q', &["c"]),
+                allocation("c", 'r'..='z', &[]),
+            ]
+        );
+
+        // Nobody can tell how far they got, the range waits for one of its replicas to answer
+        let alive = vec!["c".to_string()];
+        assert_eq!(
+            fail_over(&allocations, &alive, 1, |_, _| None)[0],
+            allocations[0]
+        );
+    }
+
+    #[test]
+    fn test_replicas_that_are_gone_are_replaced() {
+        let allocation = |node: &str, replicas: &[&str]| NamespaceAllocation {
+            node: node.to_string(),
+            range: 'a'..='z',
+            replicas: replicas.iter().map(|replica| replica.to_string()).collect(),
+        };
+        let allocations = vec![
+            allocation("a", &["b"]),
+            allocation("b", &["a"]),
+            allocation("c", &["a"]),
+            allocation("d", &["c"]),
+        ];
+        let sequences = |_: &str, _: &str| Some(1);
+
+        // `b` takes over the range of `a` and, like `c`, has no replica left. They get the nodes
+        // replicating the fewest ranges at the time, and `d` one more next to `c`.
+        let alive = vec!["b".to_string(), "c".to_string(), "d".to_string()];
+        assert_eq!(
+            fail_over(&allocations, &alive, 2, sequences),
+            vec![
+                allocation("b", &["d", "c"]),
+                allocation("b", &["d", "c"]),
+                allocation("c", &["b", "d"]),
+                allocation("d", &["c", "b"]),
+            ]
+        );
+
+        // Not enough nodes left, the owner keeps what there is
+        let alive = vec!["d".to_string()];
+        assert_eq!(
+            fail_over(&allocations[3..], &alive, 2, sequences),
+            vec![allocation("d", &[])]
+        );
+    }
+
+    #[test]
+    fn test_replicas_that_dont_answer_are_skipped() {
+        // Accepts the connection but never answers
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let started = Instant::now();
+        assert_eq!(replicated_sequence(&address, "localhost:1337"), None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_candidates_wait_for_the_one_right_before_them() {
+        let candidates: Vec<String> = [
+            "candidate-0000000007",
+            "candidate-0000000003",
+            "candidate-0000000005",
+        ]
+        .iter()
+        .map(|candidate| candidate.to_string())
+        .collect();
+
+        assert_eq!(predecessor(&candidates, "candidate-0000000003"), None);
+        assert_eq!(
+            predecessor(&candidates, "candidate-0000000007"),
+            Some("candidate-0000000005".to_string())
+        );
+    }
+
+    #[test]
+    fn test_only_conflicting_writes_to_the_allocations_depose_the_leader() {
+        assert!(deposed(&ZkError::BadVersion));
+        assert!(deposed(&ZkError::NodeExists));
+        assert!(!deposed(&ZkError::ConnectionLoss));
     }
 }
@@ -1,496 +1,1962 @@
-use clap::Parser;
-use regex::Regex;
-use rustkv::{Command, Connect, ConnectOk, Message, ReplicationCommand};
-use rustkv::{NamespaceAllocation, Node};
+use clap::{Parser, ValueEnum};
+use rustkv::command_log::{CommandLog, Durability};
+use rustkv::eviction::{Eviction, EvictionPolicy};
+use rustkv::lsm::{Levels, LsmEngine, LsmOptions};
+use rustkv::protocol::{self, MessageReader, MessageWriter, Protocol};
+use rustkv::snapshot::{self, Snapshot};
+use rustkv::storage::{MemoryEngine, StorageEngine};
+use rustkv::unix_time_ms;
+use rustkv::ResponseBody;
+use rustkv::{Command, Connect, ConnectOk, Message, ReplicationAck, ReplicationCommand};
+use rustkv::{Entry, Error, ErrorCode, Info, NamespaceAllocation, Node, Request, Response};
 use std::cell::RefCell;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader, Result as IOResult, Write};
-use std::net::TcpStream;
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::vec;
-use std::{collections::HashMap, net::TcpListener, time::Duration};
-use zookeeper::{Acl, CreateMode, WatchedEvent, Watcher, ZooKeeper};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::ops::{Bound, RangeInclusive};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use std::{net::TcpListener, time::Duration};
+use zookeeper::{Acl, CreateMode, WatchedEvent, Watcher, ZkError, ZooKeeper};
 
 struct ReplicationPeer {
     pub peer: String,
-    pub stream: TcpStream,
+    pub stream: MessageWriter<TcpStream>,
+    pub address: SocketAddr, // address of the connection, tells apart reconnections of a peer
+    pub acked: usize,        // sequence of the last command the peer acknowledged
+    pub caught_up_at: usize, // sequence the peer has to acknowledge to be caught up
 }
 
 impl ReplicationPeer {
-    fn new(peer: &String, stream: TcpStream) -> Self {
+    fn new(
+        peer: &str,
+        stream: MessageWriter<TcpStream>,
+        acked: usize,
+        caught_up_at: usize,
+    ) -> Self {
         ReplicationPeer {
-            peer: peer.clone(),
+            peer: peer.to_string(),
+            address: stream.get_ref().peer_addr().unwrap(),
             stream,
+            acked,
+            caught_up_at,
         }
     }
 
+    fn is_caught_up(&self) -> bool {
+        self.acked >= self.caught_up_at
+    }
+
     fn replicate(&mut self, command: Command, sequence: usize) -> IOResult<()> {
         println!("Replicate ");
-        let response = self.stream.write_all(
-            format!(
-                "{}\n",
-                serde_json::to_string(&Message::ReplicationCommand(ReplicationCommand {
-                    command,
-                    sequence,
-                }))
-                .unwrap()
-            )
-            .as_bytes(),
-        );
-
-        response
+        self.stream
+            .write_message(&Message::ReplicationCommand(ReplicationCommand {
+                command,
+                sequence,
+            }))
     }
 }
-pub(crate) struct KV {
-    pub map: HashMap<String, String>,
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Consistency {
+    // Reply as soon as the command is in the local log
+    Async,
+    // Wait until one replica has applied the command
+    One,
+    // Wait until every connected replica has applied the command
+    All,
 }
 
-impl KV {
-    pub fn new(map: HashMap<String, String>) -> KV {
-        KV { map }
-    }
-
-    // TODO: this method should be moved to the `CommandLog`
-    pub fn init_from_logfile(filename: &String) -> KV {
-        let mut map = HashMap::new();
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)
-            .unwrap();
-        let lines = BufReader::new(file).lines();
-        let set_regex = Regex::new(r"\d+#(\w+)=(\w+)").unwrap();
-        let del_regex = Regex::new(r"\d+#DEL (\w+)").unwrap();
-
-        lines.for_each(|line| {
-            if let Ok(line) = line {
-                // This patter of `let x = foo else bar` is called let-else. https://rust-lang.github.io/rfcs/3137-let-else.html
-                if let Some(capture) = set_regex.captures(line.as_str()) {
-                    println!("Key {}, Value {}", &capture[1], &capture[2]);
-                    map.insert(String::from(&capture[1]), String::from(&capture[2]));
-                } else if let Some(capture) = del_regex.captures(line.as_str()) {
-                    println!("Del Key {}", &capture[1]);
-                    map.remove(&capture[1]);
-                };
+/*
+ * Replicas connected to this node. Commands are sent to them as they are applied and they
+ * acknowledge them back once applied on their side, which is what writers wait for depending on
+ * the `Consistency` of the node.
+ *
+ * The node is read-only while any of the `expected` replicas is not connected or still catching
+ * up. Otherwise it would accept writes that some replica might never get.
+ */
+struct Replication {
+    peers: Mutex<Vec<ReplicationPeer>>,
+    acked: Condvar,
+    expected: RwLock<Vec<String>>,
+    read_only: AtomicBool,
+    consistency: Consistency,
+    timeout: Duration,
+}
+
+impl Replication {
+    fn new(expected: Vec<String>, consistency: Consistency, timeout: Duration) -> Replication {
+        let replication = Replication {
+            peers: Mutex::new(Vec::new()),
+            acked: Condvar::new(),
+            read_only: AtomicBool::new(false),
+            expected: RwLock::new(expected),
+            consistency,
+            timeout,
+        };
+
+        replication.update_read_only(&replication.peers.lock().unwrap());
+        replication
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    // Must be called every time the peers change or acknowledge a command
+    fn update_read_only(&self, peers: &[ReplicationPeer]) {
+        let read_only = self.expected.read().unwrap().iter().any(|expected| {
+            !peers.iter().any(|replication_peer| {
+                &replication_peer.peer == expected && replication_peer.is_caught_up()
+            })
+        });
+
+        if self.read_only.swap(read_only, Ordering::AcqRel) != read_only {
+            println!("Read-only mode {}", if read_only { "on" } else { "off" });
+        }
+    }
+
+    // Replaces the replicas the node expects, when the coordinator assigns it new ones
+    fn expect(&self, expected: Vec<String>) {
+        let mut peers = self.peers.lock().unwrap();
+
+        // Replicas that are no longer assigned to the node stop getting its commands
+        peers.retain(|replication_peer| {
+            let keep = expected.contains(&replication_peer.peer);
+            if !keep {
+                println!("Disconnecting replica {}", replication_peer.peer);
+                let _ = replication_peer.stream.get_ref().shutdown(Shutdown::Both);
             }
+
+            keep
         });
 
-        KV::new(map)
+        *self.expected.write().unwrap() = expected;
+        self.update_read_only(&peers);
+    }
+
+    fn register(&self, peer: ReplicationPeer) {
+        let mut peers = self.peers.lock().unwrap();
+
+        // A previous connection from the same peer is dead even if we haven't noticed yet
+        peers.retain(|replication_peer| replication_peer.peer != peer.peer);
+        peers.push(peer);
+        self.update_read_only(&peers);
+    }
+
+    fn unregister(&self, peer: &str, address: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+
+        peers.retain(|replication_peer| {
+            replication_peer.peer != peer || replication_peer.address != address
+        });
+        self.update_read_only(&peers);
+        self.acked.notify_all();
     }
 
-    pub fn set(&mut self, key: String, value: String) {
-        self.map.insert(key, value);
+    fn ack(&self, peer: &str, sequence: usize) {
+        let mut peers = self.peers.lock().unwrap();
+
+        for replication_peer in peers.iter_mut() {
+            if replication_peer.peer == peer {
+                replication_peer.acked = replication_peer.acked.max(sequence);
+            }
+        }
+
+        self.update_read_only(&peers);
+        self.acked.notify_all();
     }
 
-    pub fn get(&self, key: &String) -> Option<&String> {
-        self.map.get(key)
+    // Sends the command to every replica. Returns the replicas it was sent to.
+    fn replicate(&self, command: &Command, sequence: usize) -> Vec<String> {
+        let mut sent = Vec::new();
+
+        let mut peers = self.peers.lock().unwrap();
+
+        // This is needed when the the stream opened for the REPL or webserver
+        // handle an error after trying to write to a closed socket because the peer is gone.
+        peers.retain_mut(|replication_peer| {
+            println!("Replicate to {}", replication_peer.peer);
+            match replication_peer.replicate(command.clone(), sequence) {
+                Ok(_) => {
+                    sent.push(replication_peer.peer.clone());
+                    true
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    false
+                }
+            }
+        });
+
+        self.update_read_only(&peers);
+        sent
     }
 
-    // TODO take a &str instead
-    pub fn del(&mut self, key: &String) {
-        self.map.remove(key);
+    // Blocks until as many of `peers` as the consistency level requires have acknowledged
+    // `sequence`. Returns `false` if that doesn't happen before the timeout or if too many of them
+    // disconnect in the meantime.
+    fn wait(&self, peers: &[String], sequence: usize) -> bool {
+        let required = match self.consistency {
+            Consistency::Async => return true,
+            Consistency::One => 1,
+            Consistency::All => peers.len(),
+        };
+        let deadline = Instant::now() + self.timeout;
+        let mut replication_peers = self.peers.lock().unwrap();
+
+        loop {
+            let connected = replication_peers
+                .iter()
+                .filter(|replication_peer| peers.contains(&replication_peer.peer));
+            let acked = connected
+                .clone()
+                .filter(|replication_peer| replication_peer.acked >= sequence)
+                .count();
+
+            if acked >= required {
+                return true;
+            }
+
+            let now = Instant::now();
+            if connected.count() < required || now >= deadline {
+                return false;
+            }
+
+            replication_peers = self
+                .acked
+                .wait_timeout(replication_peers, deadline - now)
+                .unwrap()
+                .0;
+        }
     }
 }
 
-pub(crate) struct CommandLog {
-    file: File,       // backing file to store the commands
-    filename: String, // name of the file
-    sequence: usize,
+/*
+ * The ranges allocated to the node by the coordinator: the ones it owns, which it accepts commands
+ * for, and the ones of other owners it replicates.
+ */
+struct Ranges {
+    owned: RwLock<Vec<NamespaceAllocation>>,
+    replicating: Mutex<HashMap<String, Replicating>>,
 }
 
-fn upsert_logfile(filename: &String) -> File {
-    File::options()
-        .append(true)
-        .create(true)
-        .open(filename)
-        .unwrap()
+// Replication of the range of another owner, over the connection the node opened to it
+struct Replicating {
+    // Kept to close the connection when the node takes over the range
+    stream: TcpStream,
+    // Sequence of the last command of the owner that was applied
+    sequence: Arc<AtomicUsize>,
+    // Hands back the replicated KV once the connection is closed
+    thread: JoinHandle<KV>,
 }
 
-fn count_lines(filename: &String) -> usize {
-    // NOTE: I wanted to pass the `File` returned from `upsert_logfile` when I did so the
-    // could would hang. The CommandLog code wouldn't execute pass the call to this
-    // function.
-    // TODO: read only the last line and get its sequence value
-    BufReader::new(File::open(filename).unwrap())
-        .lines()
-        .count()
+impl Ranges {
+    // A key of the command that the node doesn't own
+    fn unowned_key<'a>(&self, command: &'a Command) -> Option<&'a [u8]> {
+        let owned = self.owned.read().unwrap();
+
+        command
+            .keys()
+            .into_iter()
+            .find(|key| !owned.iter().any(|allocation| allocation.contains(key)))
+    }
+
+    fn replicated_sequence(&self, owner: &str) -> Option<usize> {
+        self.replicating
+            .lock()
+            .unwrap()
+            .get(owner)
+            .map(|replicating| replicating.sequence.load(Ordering::Acquire))
+    }
 }
 
-impl CommandLog {
-    pub fn new(filename: String) -> CommandLog {
-        let file = upsert_logfile(&filename);
-        let sequence = count_lines(&filename);
+// What a write did. Only `Applied` and `Incremented` changed the KV, the rest were evaluated and
+// rejected without logging anything.
+enum WriteOutcome {
+    Applied { sequence: usize },
+    // The `Increment` was applied and the counter has the new `value`
+    Incremented { sequence: usize, value: i64 },
+    // EXPIRE or PERSIST of a key that doesn't exist
+    NotFound,
+    // The condition of a conditional write doesn't hold
+    ConditionFailed,
+    // Increment of a value that is not an integer or whose result doesn't fit in one
+    NotAnInteger,
+    Overflow,
+    // The write doesn't fit in the memory limit and the policy can't evict enough keys
+    OutOfMemory,
+}
 
-        println!("Sequence {}", sequence);
+enum WriteError {
+    // Some of the replicas of the node are not available
+    ReadOnly,
+    // Not enough replicas acknowledged the command in time. The command was applied by this node,
+    // so it might still reach them.
+    NotReplicated,
+}
 
-        CommandLog {
-            file,
-            filename,
-            sequence,
+fn write_reply(
+    stream: &mut MessageWriter<TcpStream>,
+    id: u64,
+    result: Result<WriteOutcome, WriteError>,
+) -> IOResult<()> {
+    let body = match result {
+        Ok(WriteOutcome::Applied { sequence }) => ResponseBody::Ok { sequence },
+        Ok(WriteOutcome::Incremented { sequence, value }) => {
+            ResponseBody::Counter { value, sequence }
         }
+        Ok(WriteOutcome::NotFound) => ResponseBody::NotFound,
+        Ok(WriteOutcome::ConditionFailed) => ResponseBody::ConditionFailed,
+        Ok(WriteOutcome::NotAnInteger) => ResponseBody::Error(Error::new(
+            ErrorCode::NotAnInteger,
+            "the value is not a 64-bit signed integer",
+        )),
+        Ok(WriteOutcome::Overflow) => ResponseBody::Error(Error::new(
+            ErrorCode::Overflow,
+            "the increment overflows a 64-bit signed integer",
+        )),
+        Ok(WriteOutcome::OutOfMemory) => ResponseBody::Error(Error::new(
+            ErrorCode::OutOfMemory,
+            "the write doesn't fit in the memory limit of the node",
+        )),
+        Err(WriteError::ReadOnly) => ResponseBody::Error(Error::new(
+            ErrorCode::ReadOnly,
+            "some replicas are unavailable, the node is read-only",
+        )),
+        Err(WriteError::NotReplicated) => ResponseBody::Error(Error::new(
+            ErrorCode::NotReplicated,
+            "not enough replicas acknowledged the write in time",
+        )),
+    };
+
+    stream.write_message(&Message::Response(Response { id, body }))
+}
+
+// Entries of a scan and the cursor to continue it from
+type ScanPage = (Vec<(Vec<u8>, Entry)>, Option<Vec<u8>>);
+
+pub(crate) struct KV {
+    // Expired keys are hidden right away but stay in the engine until the next sweep
+    engine: Box<dyn StorageEngine>,
+    // Memory taken by the entries. Reads count as uses of the keys, so it has its own lock to be
+    // updated while the KV is only locked for reading.
+    eviction: Mutex<Eviction>,
+    // Without a memory limit the entries are not tracked, and reads don't take the lock
+    limited: bool,
+    // Keys and bytes in the engine when there is no memory limit, counted as they are written
+    keys: usize,
+    used_bytes: u64,
+}
+
+impl KV {
+    pub fn new(engine: Box<dyn StorageEngine>) -> KV {
+        let mut kv = KV {
+            engine,
+            eviction: Mutex::new(Eviction::new(EvictionPolicy::NoEviction, None)),
+            limited: false,
+            keys: 0,
+            used_bytes: 0,
+        };
+
+        kv.recount();
+        kv
+    }
+
+    // Rebuilds the accounting of the memory from the entries in the engine
+    fn recount(&mut self) {
+        let eviction = self.eviction.get_mut().unwrap();
+        eviction.clear();
+        self.keys = 0;
+        self.used_bytes = 0;
+
+        for (key, entry) in self.engine.scan(Bound::Unbounded, Bound::Unbounded) {
+            let bytes = (key.len() + entry.value.len()) as u64;
+
+            if self.limited {
+                eviction.written(&key, bytes, entry.expires_at);
+            } else {
+                self.keys += 1;
+                self.used_bytes += bytes;
+            }
+        }
+    }
+
+    // Counts the key as taking `bytes` from now on, `None` once it's deleted. Only needed when
+    // the keys are not tracked for eviction.
+    fn count(&mut self, key: &[u8], bytes: Option<u64>) {
+        if self.limited {
+            return;
+        }
+
+        if let Some(previous) = self.engine.get(key) {
+            self.keys -= 1;
+            self.used_bytes -= (key.len() + previous.value.len()) as u64;
+        }
+
+        if let Some(bytes) = bytes {
+            self.keys += 1;
+            self.used_bytes += bytes;
+        }
+    }
+
+    pub fn limit_memory(&mut self, policy: EvictionPolicy, max_bytes: Option<u64>) {
+        self.eviction.get_mut().unwrap().limit(policy, max_bytes);
+        self.limited = max_bytes.is_some();
+        self.recount();
+    }
+
+    // Replaces the contents of the KV with the snapshot
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.engine.restore(snapshot);
+        self.recount();
+    }
+
+    // Loads the latest snapshot of the log into the engine, unless the engine kept its entries,
+    // and replays the commands logged after them
+    pub fn init_from_log(engine: Box<dyn StorageEngine>, command_log: &CommandLog) -> KV {
+        let mut kv = KV::new(engine);
+        let sequence = match (kv.engine.durable_sequence(), command_log.latest_snapshot()) {
+            (Some(sequence), _) => {
+                println!("Engine sequence {}", sequence);
+                sequence
+            }
+            (None, Some(snapshot)) => {
+                let sequence = snapshot.sequence;
+                println!("Snapshot sequence {}", sequence);
+                kv.restore(snapshot);
+                sequence
+            }
+            (None, None) => 0,
+        };
+
+        command_log.replay(sequence, |sequence, command| {
+            println!("Replay {}", command);
+            kv.apply(&command, sequence);
+        });
+
+        kv.sweep(unix_time_ms());
+        kv
     }
 
-    pub fn append(&mut self, command: &Command) -> usize {
+    // Applies a command read from the log at the given sequence, which becomes the version of the
+    // key
+    pub fn apply(&mut self, command: &Command, sequence: usize) {
         match command {
-            Command::Set { ref key, ref value } => {
-                writeln!(&mut self.file, "{}#{}={}", self.sequence, key, value).unwrap();
+            Command::Set { key, value } => self.set(key.clone(), value.clone(), None, sequence),
+            Command::Delete { key } => self.del(key),
+            Command::SetExpiring {
+                key,
+                value,
+                expires_at,
+            } => self.set(key.clone(), value.clone(), Some(*expires_at), sequence),
+            Command::ExpireAt { key, expires_at } => {
+                if let Some(entry) = self.engine.get(key) {
+                    self.set(key.clone(), entry.value, Some(*expires_at), sequence);
+                }
+            }
+            Command::Persist { key } => {
+                if let Some(entry) = self.engine.get(key) {
+                    self.set(key.clone(), entry.value, None, sequence);
+                }
+            }
+            Command::MultiSet { entries } => {
+                for (key, value) in entries {
+                    self.set(key.clone(), value.clone(), None, sequence);
+                }
+            }
+            Command::MultiDelete { keys } => {
+                for key in keys {
+                    self.del(key);
+                }
             }
-            Command::Delete { ref key } => {
-                writeln!(&mut self.file, "{}#DEL {}", self.sequence, key).unwrap();
+            // The versions of the previous owner come from its own log, they are replaced so that
+            // the versions of the keys keep going up
+            Command::TakeOver { entries } => {
+                for (key, entry) in entries {
+                    self.set(key.clone(), entry.value.clone(), entry.expires_at, sequence);
+                }
             }
-            _ => panic!("Can't log this command"),
+            _ => panic!("Can't apply this command"),
         }
 
-        self.sequence += 1;
-        self.sequence
+        self.engine.applied(sequence);
     }
 
-    pub fn replicated_append(&mut self, command: &Command, sequence: usize) -> usize {
+    // Turns a command sent by a client into the command to log, checking it against the current
+    // contents of the KV
+    fn resolve(&self, command: Command) -> Result<Command, WriteOutcome> {
         match command {
-            Command::Set { ref key, ref value } => {
-                writeln!(&mut self.file, "{}#{}={}", sequence, key, value).unwrap();
+            Command::ExpireAt { ref key, .. } | Command::Persist { ref key }
+                if self.get(key).is_none() =>
+            {
+                Err(WriteOutcome::NotFound)
+            }
+            Command::CompareAndSet { key, expected, new } => {
+                if self.get(&key).map(|entry| entry.value) != Some(expected) {
+                    return Err(WriteOutcome::ConditionFailed);
+                }
+
+                Ok(Command::Set { key, value: new })
+            }
+            Command::SetIfAbsent { key, value } => {
+                if self.get(&key).is_some() {
+                    return Err(WriteOutcome::ConditionFailed);
+                }
+
+                Ok(Command::Set { key, value })
+            }
+            Command::DeleteIfEquals { key, expected } => {
+                if self.get(&key).map(|entry| entry.value) != Some(expected) {
+                    return Err(WriteOutcome::ConditionFailed);
+                }
+
+                Ok(Command::Delete { key })
+            }
+            Command::SetIfVersion {
+                key,
+                value,
+                if_version,
+            } => {
+                if self.get(&key).map(|entry| entry.version) != Some(if_version) {
+                    return Err(WriteOutcome::ConditionFailed);
+                }
+
+                Ok(Command::Set { key, value })
             }
-            Command::Delete { ref key } => {
-                writeln!(&mut self.file, "{}#DEL {}", sequence, key).unwrap();
+            Command::DeleteIfVersion { key, if_version } => {
+                if self.get(&key).map(|entry| entry.version) != Some(if_version) {
+                    return Err(WriteOutcome::ConditionFailed);
+                }
+
+                Ok(Command::Delete { key })
             }
-            _ => panic!("Can't log this command"),
+            command => Ok(command),
         }
+    }
 
-        sequence
+    // Resolves an `Increment` into the command that sets the new value of the counter, which
+    // keeps the expiration of the key
+    fn increment(&self, key: Vec<u8>, delta: i64) -> Result<(Command, i64), WriteOutcome> {
+        let (current, expires_at) = match self.get(&key) {
+            Some(entry) => (
+                std::str::from_utf8(&entry.value)
+                    .ok()
+                    .and_then(|value| value.parse::<i64>().ok())
+                    .ok_or(WriteOutcome::NotAnInteger)?,
+                entry.expires_at,
+            ),
+            None => (0, None),
+        };
+        let value = current.checked_add(delta).ok_or(WriteOutcome::Overflow)?;
+        let bytes = value.to_string().into_bytes();
+
+        let command = match expires_at {
+            Some(expires_at) => Command::SetExpiring {
+                key,
+                value: bytes,
+                expires_at,
+            },
+            None => Command::Set { key, value: bytes },
+        };
+
+        Ok((command, value))
     }
 
-    pub fn filename(&self) -> &String {
-        &self.filename
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>, version: usize) {
+        let bytes = (key.len() + value.len()) as u64;
+
+        self.count(&key, Some(bytes));
+        self.eviction
+            .get_mut()
+            .unwrap()
+            .written(&key, bytes, expires_at);
+        self.engine.put(
+            key,
+            Entry {
+                value,
+                version,
+                expires_at,
+            },
+        );
     }
-}
 
-fn handle_replica_stream(stream: TcpStream, kv: KV, command_log: CommandLog) {
-    let stream = RefCell::new(stream);
-    let stream_ref = &stream;
-    let kv = &RefCell::new(kv);
-    let command_log = &RefCell::new(command_log);
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        let now = unix_time_ms();
 
-    let buf_reader = BufReader::new(stream_ref.borrow_mut().try_clone().unwrap());
+        self.engine
+            .get(key)
+            .filter(|entry| entry.expires_at.is_none_or(|expires_at| expires_at > now))
+    }
 
-    for line in buf_reader.lines() {
-        let request_line_string = line.unwrap();
-        println!("MESSAGE {}", request_line_string);
-        if request_line_string == "PING" {
-            println!("PING request");
-            stream_ref.borrow_mut().write_all(b"PONG\n").unwrap();
-            continue;
-        }
+    // `None` if the key doesn't exist, otherwise the time left until it expires, if it does
+    pub fn ttl(&self, key: &[u8]) -> Option<Option<u64>> {
+        let now = unix_time_ms();
 
-        let message = serde_json::from_str::<Message>(&request_line_string).unwrap();
-        println!("{:?}", message);
+        self.get(key).map(|entry| {
+            entry
+                .expires_at
+                .map(|expires_at| expires_at.saturating_sub(now))
+        })
+    }
 
-        match message {
-            Message::ConnectOk(ConnectOk { map }) => {
-                for (key, value) in map {
-                    kv.borrow_mut().set(key, value)
-                }
-            }
-            Message::ReplicationCommand(replication) => {
-                println!("Replication {:?}", replication);
-                let command = replication.command;
-                let sequence = replication.sequence;
+    /*
+     * Live entries from `start` to `end` in key order, at most `limit` of them, resuming after the
+     * cursor if there is one. The returned cursor is the last key of the page when there are more
+     * keys after it.
+     */
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        cursor: Option<&[u8]>,
+    ) -> ScanPage {
+        let now = unix_time_ms();
+        let from = match cursor {
+            Some(cursor) if cursor >= start => Bound::Excluded(cursor),
+            _ => Bound::Included(start),
+        };
+        let to = end.map_or(Bound::Unbounded, Bound::Excluded);
 
-                match command {
-                    Command::Set { ref key, ref value } => {
-                        println!("KV server: SET {} = {}", key, value);
-                        // let sequence = command_log.write().unwrap().append(Command::Set {
-                        //     key: key.clone(),
-                        //     value: value.clone(),
-                        // });
-
-                        let sequence = command_log
-                            .borrow_mut()
-                            .replicated_append(&command, sequence);
+        let limit = limit.max(1);
+        let mut entries: Vec<(Vec<u8>, Entry)> = self
+            .engine
+            .scan(from, to)
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|expires_at| expires_at > now))
+            .take(limit + 1)
+            .collect();
 
-                        println!("Sequence {}", sequence);
-                        kv.borrow_mut().set(key.clone(), value.clone());
-                    }
-                    Command::Get { key: _ } => {
-                        panic!("GET commands can't be replicated")
-                    }
-                    Command::Delete { ref key } => {
-                        println!("KV server: DEL {}", key);
-                        command_log
-                            .borrow_mut()
-                            .replicated_append(&command, sequence);
-                        kv.borrow_mut().del(key);
-                    }
-                }
-            }
-            _ => panic!("Unhandled message"),
+        let cursor = if entries.len() > limit {
+            entries.pop();
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+
+        (entries, cursor)
+    }
+
+    pub fn del(&mut self, key: &[u8]) {
+        self.count(key, None);
+        self.eviction.get_mut().unwrap().removed(key);
+        self.engine.delete(key);
+    }
+
+    // Counts a read of the key as a use of it for the eviction policy
+    pub fn touch(&self, key: &[u8]) {
+        if self.limited {
+            self.eviction.lock().unwrap().used(key);
         }
     }
 
-    // This is needed when the exiting thread is one handle a connection for a replication peer
-    // if let Some(replication_peer) = replication_peer {
-    //     replication_peers
-    //         .write()
-    //         .unwrap()
-    //         .swap_remove(replication_peer);
-    // }
-}
+    /*
+     * Picks the keys to evict for the command to fit in the memory limit. They are returned as the
+     * command that deletes them, which has to be logged and replicated before the command itself.
+     * Only commands that add bytes are checked, so deletes always go through.
+     */
+    fn make_room(&self, command: &Command) -> Result<Option<Command>, WriteOutcome> {
+        if !self.limited {
+            return Ok(None);
+        }
 
-fn handle_stream(
-    stream: TcpStream,
-    kv: Arc<RwLock<KV>>,
-    command_log: Arc<RwLock<CommandLog>>,
-    replication_peers: Arc<RwLock<Vec<ReplicationPeer>>>,
-) {
-    let stream = RefCell::new(stream);
-    let stream_ref = &stream;
-    // TODO understand why "stream_ref.borrow_mut" fails but "stream_ref.borrow_mut.try_clone"
-    // works
-    let buf_reader = BufReader::new(stream_ref.borrow_mut().try_clone().unwrap());
-    let mut replication_peer: Option<usize> = None;
-
-    for line in buf_reader.lines() {
-        let request_line_string = line.unwrap();
-        println!("MESSAGE {}", request_line_string);
-        if request_line_string == "PING" {
-            println!("PING request");
-            stream_ref.borrow_mut().write_all(b"PONG\n").unwrap();
-            continue;
+        let mut eviction = self.eviction.lock().unwrap();
+        let written: Vec<(&[u8], usize)> = match command {
+            Command::Set { key, value } | Command::SetExpiring { key, value, .. } => {
+                vec![(key, value.len())]
+            }
+            Command::MultiSet { entries } => entries
+                .iter()
+                .map(|(key, value)| (key.as_slice(), value.len()))
+                .collect(),
+            Command::TakeOver { entries } => entries
+                .iter()
+                .map(|(key, entry)| (key.as_slice(), entry.value.len()))
+                .collect(),
+            _ => return Ok(None),
+        };
+
+        let growth: i64 = written
+            .iter()
+            .map(|(key, value_bytes)| (key.len() + value_bytes) as i64 - eviction.bytes(key) as i64)
+            .sum();
+        if growth <= 0 {
+            return Ok(None);
         }
 
-        let message = serde_json::from_str::<Message>(&request_line_string).unwrap();
-        let mut dead_peers: Vec<usize> = Vec::new();
-        println!("{:?}", message);
+        let keep: Vec<&[u8]> = written.iter().map(|(key, _)| *key).collect();
 
-        match message {
-            Message::Command(command) => {
-                match command {
-                    // TODO: this commands can't be handled by a replica because the
-                    // repl is sending the commands to the wrong host (the leader) and because
-                    // the replica is not listening to any other connection than the one opened
-                    // with the leader
-                    Command::Set { ref key, ref value } => {
-                        println!("KV server: SET {} = {}", key, value);
-                        // let sequence = command_log.write().unwrap().append(Command::Set {
-                        //     key: key.clone(),
-                        //     value: value.clone(),
-                        // });
-
-                        let sequence = command_log.write().unwrap().append(&command);
+        match eviction.victims(growth as u64, &keep) {
+            None => Err(WriteOutcome::OutOfMemory),
+            Some(victims) if victims.is_empty() => Ok(None),
+            Some(victims) => {
+                println!("Evicting {} keys", victims.len());
+                eviction.evicted(victims.len());
 
-                        println!("Sequence {}", sequence);
-                        kv.write().unwrap().set(key.clone(), value.clone());
-                        // stream_ref.borrow_mut().write_all(
-                        //     serde_json::to_string(&ReplicationCommand { command, sequence })
-                        //         .unwrap()
-                        //         .as_bytes(),
-                        // );
-                        let mut replication_peers = replication_peers.write().unwrap();
-                        for (index, replication_peer) in replication_peers.iter_mut().enumerate() {
-                            println!("Replicate to {}", replication_peer.peer);
-                            match replication_peer.replicate(command.clone(), sequence) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    println!("{}", e);
-                                    dead_peers.push(index);
-                                }
-                            }
-                        }
-                    }
-                    Command::Get { key } => {
-                        println!("KV server: GET {}", key);
-
-                        match kv.read().unwrap().get(&key) {
-                            Some(value) => stream_ref
-                                .borrow_mut()
-                                .write_all(format!("{value}\n").as_bytes())
-                                .unwrap(),
-                            None => stream_ref.borrow_mut().write_all(b"__none__\n").unwrap(),
-                        }
-                    }
-                    Command::Delete { ref key } => {
-                        println!("KV server: DEL {}", key);
-                        let sequence = command_log.write().unwrap().append(&command);
-                        kv.write().unwrap().del(key);
-
-                        let mut replication_peers = replication_peers.write().unwrap();
-                        for (index, replication_peer) in replication_peers.iter_mut().enumerate() {
-                            println!("Replicate to {}", replication_peer.peer);
-                            match replication_peer.replicate(command.clone(), sequence) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    println!("{}", e);
-                                    dead_peers.push(index);
-                                }
-                            }
-                        }
-                    }
-                }
+                Ok(Some(Command::MultiDelete { keys: victims }))
             }
-            Message::Connect(data) => match data {
-                // TODO: can I do the matching on the line above instead of using another
-                // `match` expression
-                Connect { from } => {
-                    println!("Connect from {}", from);
-                    replication_peers
-                        .write()
-                        .unwrap()
-                        .push(ReplicationPeer::new(
-                            &from,
-                            stream_ref.borrow().try_clone().unwrap(),
-                        ));
-
-                    replication_peer = Some(replication_peers.read().unwrap().len() - 1);
-
-                    stream_ref
-                        .borrow_mut()
-                        .write_all(
-                            format!(
-                                "{}\n",
-                                serde_json::to_string(&Message::ConnectOk(ConnectOk {
-                                    // TODO: do not read directly the map from the KV
-                                    map: kv.read().unwrap().map.clone()
-                                }))
-                                .unwrap()
-                            )
-                            .as_bytes(),
-                        )
-                        .unwrap()
-                }
-                _ => panic!("Unexpected connect payload"),
-            },
-            _ => panic!("Unexpected message"),
         }
+    }
 
-        // This is needed when the the stream opened for the REPL or webserver
-        // handle an error after trying to write to a closed socket because the peer is gone.
-        for dead_peer in dead_peers {
-            replication_peers.write().unwrap().swap_remove(dead_peer);
+    pub fn info(&self) -> Info {
+        if !self.limited {
+            return Info {
+                keys: self.keys,
+                used_bytes: self.used_bytes,
+                max_bytes: None,
+                evicted_keys: self.eviction.lock().unwrap().evicted_keys(),
+            };
+        }
+
+        let eviction = self.eviction.lock().unwrap();
+
+        Info {
+            keys: eviction.keys(),
+            used_bytes: eviction.used_bytes(),
+            max_bytes: eviction.max_bytes(),
+            evicted_keys: eviction.evicted_keys(),
         }
     }
 
-    println!("ADIEU");
+    // Removes the keys that expired by `now`
+    pub fn sweep(&mut self, now: u64) -> usize {
+        let expired = self.engine.expired(now);
 
-    // This is needed when the exiting thread is one handle a connection for a replication peer
-    if let Some(replication_peer) = replication_peer {
-        replication_peers
-            .write()
-            .unwrap()
-            .swap_remove(replication_peer);
+        for key in &expired {
+            self.del(key);
+        }
+
+        expired.len()
     }
-}
 
-#[derive(Parser)]
-struct Args {
-    #[arg(long)]
-    port: String,
+    pub fn snapshot(&self, sequence: usize) -> Snapshot {
+        self.engine.snapshot(sequence)
+    }
 
-    // Id of the KV node
-    #[arg(long)]
-    id: u8,
+    /*
+     * Makes the entries durable if the engine keeps them on disk, and returns the sequence they
+     * cover. The log can be compacted up to it without writing a snapshot.
+     */
+    pub fn checkpoint(&mut self) -> Option<usize> {
+        self.engine.flush();
+        self.engine.durable_sequence()
+    }
 }
 
-fn open_replica_stream(address: &str) -> TcpStream {
-    loop {
-        match TcpStream::connect(address) {
-            Ok(stream) => return stream,
-            Err(_) => thread::sleep(Duration::from_millis(4000)),
+// Smallest key after every key that starts with the prefix, `None` if there is none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Some(end);
         }
     }
+
+    None
 }
 
-struct LoggingWatcher;
-impl Watcher for LoggingWatcher {
-    fn handle(&self, e: WatchedEvent) {
-        // TODO: use the info! macro
-        println!("{:?}", e)
+// Appends the command to the log, applies it to the KV and sends it to the replicas. Returns once
+// the command is durable and acknowledged by as many replicas as the consistency level requires.
+//
+// The command is logged, applied and sent while holding the lock on the KV. That way the map
+// always contains exactly the commands in the log up to its current sequence, which is what
+// snapshots rely on, and the replicas get the commands in the same order they were logged. The
+// waits happen after releasing the locks so that concurrent writers can share the same `fsync`.
+//
+// Conditional writes are resolved under the same lock, so nothing can change the key between
+// checking the condition and applying the write. The same goes for increments. Nothing is logged
+// when the command doesn't change the KV.
+fn apply_command(
+    kv: &RwLock<KV>,
+    command_log: &RwLock<CommandLog>,
+    replication: &Replication,
+    command: Command,
+) -> Result<WriteOutcome, WriteError> {
+    let command = command.with_deadline(unix_time_ms());
+
+    let (sequence, counter, log_sync, peers) = {
+        let mut kv = kv.write().unwrap();
+
+        if replication.is_read_only() {
+            return Err(WriteError::ReadOnly);
+        }
+
+        let resolved = match command {
+            Command::Increment { key, delta } => kv
+                .increment(key, delta)
+                .map(|(command, value)| (command, Some(value))),
+            command => kv.resolve(command).map(|command| (command, None)),
+        };
+        let (command, counter) = match resolved {
+            Ok(resolved) => resolved,
+            Err(outcome) => return Ok(outcome),
+        };
+        let eviction = match kv.make_room(&command) {
+            Ok(eviction) => eviction,
+            Err(outcome) => return Ok(outcome),
+        };
+
+        let mut command_log = command_log.write().unwrap();
+        let (sequence, peers) =
+            append_command(&mut kv, &mut command_log, replication, eviction, &command);
+
+        (sequence, counter, command_log.log_sync(), peers)
+    };
+
+    log_sync.wait(sequence);
+
+    if !replication.wait(&peers, sequence) {
+        return Err(WriteError::NotReplicated);
     }
+
+    Ok(match counter {
+        Some(value) => WriteOutcome::Incremented { sequence, value },
+        None => WriteOutcome::Applied { sequence },
+    })
 }
 
-pub fn main() {
-    let args = Args::parse();
-    let node_id = args.id;
-    let port = args.port;
+// Logs, applies and replicates the command, after the one that evicts the keys it needs room for.
+// Returns its sequence and the peers it was sent to.
+fn append_command(
+    kv: &mut KV,
+    command_log: &mut CommandLog,
+    replication: &Replication,
+    eviction: Option<Command>,
+    command: &Command,
+) -> (usize, Vec<String>) {
+    // Evicted keys are deleted like any other, so the replicas drop them too. The replicas
+    // acknowledging the command also acknowledge the eviction before it.
+    if let Some(eviction) = eviction {
+        let sequence = command_log.append(&eviction);
 
-    let listening_address = format!("localhost:{port}");
-    println!("Listening at {}", listening_address);
-    let listener = TcpListener::bind(&listening_address).unwrap();
-    let command_log = Arc::new(RwLock::new(CommandLog::new(format!("log.{node_id}"))));
-    let kv = Arc::new(RwLock::new(KV::init_from_logfile(
-        command_log.read().unwrap().filename(),
-    )));
-    let zk = ZooKeeper::connect("localhost:2181", Duration::from_secs(15), LoggingWatcher).unwrap();
+        kv.apply(&eviction, sequence);
+        replication.replicate(&eviction, sequence);
+    }
+    let sequence = command_log.append(command);
 
-    zk.create(
-        "/nodes/node",
-        bincode::serialize(&Node {
-            node_id,
-            address: listening_address.clone(),
-        })
-        .unwrap(),
-        Acl::open_unsafe().clone(),
-        CreateMode::EphemeralSequential,
-    )
-    .unwrap();
-
-    let (binary, _) = zk.get_data("/allocations", false).unwrap();
-    let allocations = bincode::deserialize::<Vec<NamespaceAllocation>>(&binary).unwrap();
-    let replicas: Vec<String> = allocations
-        .into_iter()
-        .filter(|allocation| allocation.node != listening_address)
-        .map(|allocation| allocation.node)
-        .collect::<Vec<String>>();
-
-    println!("Replicas {:?}", replicas);
-
-    let replication_peers: Vec<ReplicationPeer> = Vec::new();
-    let replication_peers = Arc::new(RwLock::new(replication_peers));
-
-    for replica in replicas {
-        let mut stream = open_replica_stream(&replica);
-
-        stream
-            .write_all(
-                format!(
-                    "{}\n",
-                    serde_json::to_string(&Message::Connect(Connect {
-                        // TODO: do we need to send the address of the peer. The KV could get it
-                        // from the connection
-                        from: format!("localhost:{port}") // TODO: include current sequence number in the replica
-                    }))
-                    .unwrap()
-                )
-                .as_bytes(),
-            )
-            .unwrap();
-        let replica_command_log = CommandLog::new(format!("log.{node_id}.{replica}"));
-        let replica_kv = KV::init_from_logfile(command_log.read().unwrap().filename());
+    kv.apply(command, sequence);
+
+    (sequence, replication.replicate(command, sequence))
+}
+
+// Writes a snapshot of the KV if there are commands that are not covered by the `previous` one
+// and drops them from the log. Returns the sequence covered by the latest snapshot.
+fn snapshot(kv: &RwLock<KV>, command_log: &RwLock<CommandLog>, previous: usize) -> usize {
+    // Engines that keep their entries on disk only have to flush them
+    let checkpoint = kv.write().unwrap().checkpoint();
+    if let Some(sequence) = checkpoint {
+        if sequence != previous {
+            command_log.write().unwrap().compact(sequence);
+        }
 
-        thread::spawn(|| handle_replica_stream(stream, replica_kv, replica_command_log));
+        return sequence;
     }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        println!(
-            "KV server: Accepted connection {}",
-            stream.peer_addr().unwrap()
-        );
-        /*
-         * Clone the Arc to increase the refererence count and also
-         * let the thread closure move this clone
+    let (snapshot, filename) = {
+        let kv = kv.read().unwrap();
+        let command_log = command_log.read().unwrap();
+
+        if command_log.sequence() == previous {
+            return previous;
+        }
+
+        (
+            kv.snapshot(command_log.sequence()),
+            command_log.directory().clone(),
+        )
+    };
+
+    println!("Snapshot at sequence {}", snapshot.sequence);
+    snapshot::write(&filename, &snapshot).unwrap();
+    command_log.write().unwrap().compact(snapshot.sequence);
+
+    snapshot.sequence
+}
+
+fn snapshot_periodically(
+    kv: Arc<RwLock<KV>>,
+    command_log: Arc<RwLock<CommandLog>>,
+    every: Duration,
+) {
+    let mut previous = command_log
+        .read()
+        .unwrap()
+        .latest_snapshot()
+        .map_or(0, |s| s.sequence);
+
+    loop {
+        thread::sleep(every);
+        previous = snapshot(&kv, &command_log, previous);
+    }
+}
+
+/*
+ * Next message of the connection. PINGs are answered here and frames that are not a message are
+ * answered with an `Error`. Returns `None` once the connection is closed or broken.
+ */
+fn next_message(
+    reader: &mut MessageReader<TcpStream>,
+    stream: &RefCell<MessageWriter<TcpStream>>,
+) -> Option<Message> {
+    loop {
+        let frame = match reader.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return None,
+            Err(e) => {
+                println!("{}", e);
+                return None;
+            }
+        };
+
+        if reader.protocol() == Protocol::Json && frame == b"PING" {
+            println!("PING request");
+            stream.borrow_mut().get_mut().write_all(b"PONG\n").ok()?;
+            continue;
+        }
+
+        match protocol::decode(reader.protocol(), &frame) {
+            Ok(message) => return Some(message),
+            Err(error) => {
+                println!("{:?}", error);
+                stream
+                    .borrow_mut()
+                    .write_message(&Message::Error(error))
+                    .ok()?;
+            }
+        }
+    }
+}
+
+// Drops the expired keys from the KV, which stops hiding them from then on
+fn sweep_expired_periodically(kv: Arc<RwLock<KV>>, every: Duration) {
+    loop {
+        thread::sleep(every);
+
+        let swept = kv.write().unwrap().sweep(unix_time_ms());
+        if swept > 0 {
+            println!("Swept {} expired keys", swept);
+        }
+    }
+}
+
+fn compact_periodically(levels: Weak<Levels>, every: Duration) {
+    loop {
+        thread::sleep(every);
+
+        // The engine is closed, its directory might be opened again
+        let Some(levels) = levels.upgrade() else {
+            return;
+        };
+
+        while levels.compact().unwrap() {}
+    }
+}
+
+// Applies the commands of the node the replica is connected to. Returns the KV once the connection
+// is closed.
+fn handle_replica_stream(
+    mut reader: MessageReader<TcpStream>,
+    writer: MessageWriter<TcpStream>,
+    kv: KV,
+    command_log: CommandLog,
+    replicated: Arc<AtomicUsize>,
+    snapshot_interval: Duration,
+) -> KV {
+    let stream = RefCell::new(writer);
+    let stream_ref = &stream;
+    let replica_kv = RefCell::new(kv);
+    let kv = &replica_kv;
+    let command_log = &RefCell::new(command_log);
+    let mut last_snapshot = Instant::now();
+
+    while let Some(message) = next_message(&mut reader, stream_ref) {
+        println!("{:?}", message);
+
+        let result = match message {
+            Message::ConnectOk(ConnectOk { snapshot }) => {
+                // The commands we were missing are no longer in the log of the node, start
+                // over from its snapshot
+                if let Some(snapshot) = snapshot {
+                    println!(
+                        "Replica reset to snapshot at sequence {}",
+                        snapshot.sequence
+                    );
+                    snapshot::write(command_log.borrow().directory(), &snapshot).unwrap();
+                    command_log.borrow_mut().reset(snapshot.sequence);
+                    kv.borrow_mut().restore(snapshot);
+                    last_snapshot = Instant::now();
+                }
+
+                let sequence = command_log.borrow().sequence();
+                replicated.store(sequence, Ordering::Release);
+                stream_ref
+                    .borrow_mut()
+                    .write_message(&Message::ReplicationAck(ReplicationAck { sequence }))
+            }
+            Message::ReplicationCommand(replication) => {
+                println!("Replication {:?}", replication);
+                let command = replication.command;
+                let sequence = replication.sequence;
+
+                match command {
+                    // The node might replicate a command that was already sent while catching up
+                    _ if sequence <= command_log.borrow().sequence() => (),
+                    ref command if command.is_logged() => {
+                        println!("KV server: {}", command);
+
+                        command_log
+                            .borrow_mut()
+                            .replicated_append(command, sequence);
+                        command_log.borrow().log_sync().wait(sequence);
+
+                        println!("Sequence {}", sequence);
+                        kv.borrow_mut().apply(command, sequence);
+                    }
+                    _ => {
+                        let error = Error::new(
+                            ErrorCode::UnexpectedMessage,
+                            "only logged commands can be replicated",
+                        );
+
+                        match stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Error(error))
+                        {
+                            Ok(_) => continue,
+                            Err(_) => break,
+                        }
+                    }
+                }
+
+                if last_snapshot.elapsed() >= snapshot_interval {
+                    // The replica is not read from, so expired keys are only dropped here
+                    kv.borrow_mut().sweep(unix_time_ms());
+                    let checkpoint = kv.borrow_mut().checkpoint();
+                    let covered = checkpoint.unwrap_or_else(|| {
+                        let snapshot = kv.borrow().snapshot(sequence);
+
+                        snapshot::write(command_log.borrow().directory(), &snapshot).unwrap();
+                        sequence
+                    });
+                    command_log.borrow_mut().compact(covered);
+                    last_snapshot = Instant::now();
+                }
+
+                let sequence = command_log.borrow().sequence();
+                replicated.store(sequence, Ordering::Release);
+                stream_ref
+                    .borrow_mut()
+                    .write_message(&Message::ReplicationAck(ReplicationAck { sequence }))
+            }
+            // Never answer an error with another error, the two ends would keep bouncing them
+            Message::Error(error) => {
+                println!("Error from the replicated node {:?}", error);
+                Ok(())
+            }
+            _ => stream_ref
+                .borrow_mut()
+                .write_message(&Message::Error(Error::new(
+                    ErrorCode::UnexpectedMessage,
+                    "only replication messages are expected on this connection",
+                ))),
+        };
+
+        if let Err(e) = result {
+            println!("{}", e);
+            break;
+        }
+    }
+
+    replica_kv.into_inner()
+}
+
+/*
+ * Sends the replica the commands it's missing after `sequence`, or a snapshot of the KV if they
+ * are no longer in the log, and registers it to get the commands that follow. They are sent up to
+ * the last command logged when the replica connected without holding the KV, which only blocks the
+ * writers while the commands they logged in the meantime are sent.
+ *
+ * `acked` is the last command the replica acknowledged while catching up.
+ */
+fn catch_up_replica(
+    mut stream: MessageWriter<TcpStream>,
+    from: &str,
+    sequence: usize,
+    kv: &RwLock<KV>,
+    command_log: &RwLock<CommandLog>,
+    replication: &Replication,
+    acked: &AtomicUsize,
+) -> IOResult<()> {
+    let send = |stream: &mut MessageWriter<TcpStream>, sequence, command| {
+        stream.write_message(&Message::ReplicationCommand(ReplicationCommand {
+            command,
+            sequence,
+        }))
+    };
+    let mut caught_up = sequence;
+
+    loop {
+        let (snapshot, range) = {
+            let kv = kv.read().unwrap();
+            let command_log = command_log.read().unwrap();
+            let current = command_log.sequence();
+
+            if caught_up <= current && caught_up + 1 >= command_log.first_sequence() {
+                (None, command_log.range_from(caught_up + 1)?)
+            } else {
+                (
+                    Some(kv.snapshot(current)),
+                    command_log.range_from(current + 1)?,
+                )
+            }
+        };
+
+        caught_up = range.last_sequence();
+        stream.write_message(&Message::ConnectOk(ConnectOk { snapshot }))?;
+        range.read(|sequence, command| send(&mut stream, sequence, command))?;
+
+        // Writers hold the KV lock while they append to the log. Holding it until the replica is
+        // registered guarantees that it gets every command after the ones sent here.
+        let _kv = kv.read().unwrap();
+        let command_log = command_log.read().unwrap();
+
+        // The log was compacted past what the replica got meanwhile, it gets a newer snapshot
+        if caught_up + 1 < command_log.first_sequence() {
+            continue;
+        }
+
+        command_log
+            .range_from(caught_up + 1)?
+            .read(|sequence, command| send(&mut stream, sequence, command))?;
+
+        replication.register(ReplicationPeer::new(
+            from,
+            stream,
+            sequence,
+            command_log.sequence(),
+        ));
+        // The acknowledgements read before the replica was registered
+        replication.ack(from, acked.load(Ordering::Acquire));
+
+        return Ok(());
+    }
+}
+
+fn handle_stream(
+    stream: TcpStream,
+    kv: Arc<RwLock<KV>>,
+    command_log: Arc<RwLock<CommandLog>>,
+    replication: Arc<Replication>,
+    ranges: Arc<Ranges>,
+) {
+    let (mut reader, writer) = match protocol::accept(stream) {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let stream = RefCell::new(writer);
+    let stream_ref = &stream;
+    // Set when the connection comes from a replica
+    let mut replication_peer: Option<(String, SocketAddr)> = None;
+    // Sends the replica what it's missing while its acknowledgements are read here, otherwise a
+    // long catch up fills the buffers of the connection both ways and neither end makes progress
+    let mut catching_up: Option<JoinHandle<()>> = None;
+    let acked_while_catching_up = Arc::new(AtomicUsize::new(0));
+
+    while let Some(message) = next_message(&mut reader, stream_ref) {
+        println!("{:?}", message);
+
+        let result = match message {
+            Message::Command(Request { id, ref command })
+                if ranges.unowned_key(command).is_some() =>
+            {
+                let key = ranges.unowned_key(command).unwrap();
+
+                stream_ref
+                    .borrow_mut()
+                    .write_message(&Message::Response(Response {
+                        id,
+                        body: ResponseBody::Error(Error::new(
+                            ErrorCode::WrongOwner,
+                            format!(
+                                "key {:?} is not owned by this node",
+                                String::from_utf8_lossy(key)
+                            ),
+                        )),
+                    }))
+            }
+            Message::Command(Request { id, command }) => {
+                println!("KV server: {}", command);
+
+                // TODO: this commands can't be handled by a replica because the
+                // repl is sending the commands to the wrong host (the leader) and because
+                // the replica is not listening to any other connection than the one opened
+                // with the leader
+                match command {
+                    Command::Get { key } => {
+                        let kv = kv.read().unwrap();
+                        kv.touch(&key);
+
+                        let body = match kv.get(&key) {
+                            Some(entry) => ResponseBody::Value {
+                                value: entry.value,
+                                version: entry.version,
+                            },
+                            None => ResponseBody::NotFound,
+                        };
+
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response { id, body }))
+                    }
+                    Command::MultiGet { keys } => {
+                        let kv = kv.read().unwrap();
+                        let entries = keys
+                            .iter()
+                            .map(|key| {
+                                kv.touch(key);
+                                kv.get(key)
+                            })
+                            .collect();
+
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response {
+                                id,
+                                body: ResponseBody::Values(entries),
+                            }))
+                    }
+                    Command::Scan {
+                        start,
+                        end,
+                        limit,
+                        cursor,
+                    } => {
+                        let (entries, cursor) = kv.read().unwrap().scan(
+                            &start,
+                            end.as_deref(),
+                            limit,
+                            cursor.as_deref(),
+                        );
+
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response {
+                                id,
+                                body: ResponseBody::Entries { entries, cursor },
+                            }))
+                    }
+                    Command::PrefixScan {
+                        prefix,
+                        limit,
+                        cursor,
+                    } => {
+                        let end = prefix_end(&prefix);
+                        let (entries, cursor) = kv.read().unwrap().scan(
+                            &prefix,
+                            end.as_deref(),
+                            limit,
+                            cursor.as_deref(),
+                        );
+
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response {
+                                id,
+                                body: ResponseBody::Entries { entries, cursor },
+                            }))
+                    }
+                    Command::ReplicatedSequence { owner } => {
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response {
+                                id,
+                                body: ResponseBody::ReplicatedSequence(
+                                    ranges.replicated_sequence(&owner),
+                                ),
+                            }))
+                    }
+                    Command::Info => {
+                        let info = kv.read().unwrap().info();
+
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response {
+                                id,
+                                body: ResponseBody::Info(info),
+                            }))
+                    }
+                    Command::TakeOver { .. } => {
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response {
+                                id,
+                                body: ResponseBody::Error(Error::new(
+                                    ErrorCode::UnexpectedMessage,
+                                    "only the node taking over a range writes its entries",
+                                )),
+                            }))
+                    }
+                    Command::Ttl { key } => {
+                        let body = match kv.read().unwrap().ttl(&key) {
+                            Some(remaining_ms) => ResponseBody::Ttl { remaining_ms },
+                            None => ResponseBody::NotFound,
+                        };
+
+                        stream_ref
+                            .borrow_mut()
+                            .write_message(&Message::Response(Response { id, body }))
+                    }
+                    command => {
+                        let result = apply_command(&kv, &command_log, &replication, command);
+
+                        write_reply(&mut stream_ref.borrow_mut(), id, result)
+                    }
+                }
+            }
+            Message::Connect(Connect { from, sequence }) if replication_peer.is_none() => {
+                println!("Connect from {} at sequence {}", from, sequence);
+
+                let stream = stream_ref.borrow().get_ref().try_clone().unwrap();
+                let writer = MessageWriter::new(stream, stream_ref.borrow().protocol());
+                let (kv, command_log, replication, acked) = (
+                    kv.clone(),
+                    command_log.clone(),
+                    replication.clone(),
+                    acked_while_catching_up.clone(),
+                );
+                replication_peer = Some((from.clone(), writer.get_ref().peer_addr().unwrap()));
+
+                catching_up = Some(thread::spawn(move || {
+                    let caught_up = catch_up_replica(
+                        writer,
+                        &from,
+                        sequence,
+                        &kv,
+                        &command_log,
+                        &replication,
+                        &acked,
+                    );
+
+                    if let Err(e) = caught_up {
+                        println!("Catching up {} failed: {}", from, e);
+                    }
+                }));
+                Ok(())
+            }
+            Message::ReplicationAck(ReplicationAck { sequence }) if replication_peer.is_some() => {
+                let (peer, _) = replication_peer.as_ref().unwrap();
+                acked_while_catching_up.fetch_max(sequence, Ordering::AcqRel);
+                replication.ack(peer, sequence);
+                Ok(())
+            }
+            // Never answer an error with another error, the two ends would keep bouncing them
+            Message::Error(error) => {
+                println!("Error from the connection {:?}", error);
+                Ok(())
+            }
+            _ => stream_ref
+                .borrow_mut()
+                .write_message(&Message::Error(Error::new(
+                    ErrorCode::UnexpectedMessage,
+                    "the message is not expected on this connection",
+                ))),
+        };
+
+        if let Err(e) = result {
+            println!("{}", e);
+            break;
+        }
+    }
+
+    println!("ADIEU");
+
+    // The replica must not be registered after it's gone
+    if let Some(catching_up) = catching_up {
+        let _ = stream_ref.borrow().get_ref().shutdown(Shutdown::Both);
+        catching_up.join().unwrap();
+    }
+
+    // This is needed when the exiting thread is one handle a connection for a replication peer
+    if let Some((peer, address)) = replication_peer {
+        replication.unregister(&peer, address);
+    }
+}
+
+#[derive(Parser, Clone)]
+struct Args {
+    #[arg(long)]
+    port: String,
+
+    // Id of the KV node
+    #[arg(long)]
+    id: u8,
+
+    // First characters of the keys the node proposes to own, like `a-h`
+    #[arg(long, value_parser = parse_range)]
+    range: RangeInclusive<char>,
+
+    // When the command log is flushed to stable storage
+    #[arg(long, value_enum, default_value_t = DurabilityMode::Always)]
+    durability: DurabilityMode,
+
+    // How long a group commit waits for other writes to join it
+    #[arg(long, default_value_t = 2)]
+    group_commit_window_ms: u64,
+
+    // How often the command log is flushed in `interval` mode
+    #[arg(long, default_value_t = 1000)]
+    fsync_interval_ms: u64,
+
+    // How many replicas have to apply a SET/DEL before replying to the client
+    #[arg(long, value_enum, default_value_t = Consistency::Async)]
+    consistency: Consistency,
+
+    // How long to wait for the replicas to acknowledge a SET/DEL
+    #[arg(long, default_value_t = 5000)]
+    replication_timeout_ms: u64,
+
+    // Size after which a segment of the command log rolls over
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    segment_bytes: u64,
+
+    // How often a snapshot of the KV is written to compact the command log
+    #[arg(long, default_value_t = 300)]
+    snapshot_interval_secs: u64,
+
+    // How often expired keys are removed from the KV
+    #[arg(long, default_value_t = 1000)]
+    expiry_sweep_interval_ms: u64,
+
+    // Protocol of the connections opened to the replicas
+    #[arg(long, value_enum, default_value_t = Protocol::Bincode)]
+    protocol: Protocol,
+
+    // Where the KV keeps its entries
+    #[arg(long, value_enum, default_value_t = EngineKind::Memory)]
+    storage_engine: EngineKind,
+
+    // Size after which the memtable of the `lsm` engine is flushed to disk
+    #[arg(long, default_value_t = 4 * 1024 * 1024)]
+    memtable_bytes: usize,
+
+    // How often the `lsm` engine looks for tables to compact
+    #[arg(long, default_value_t = 1000)]
+    compaction_interval_ms: u64,
+
+    // Bytes the keys and values can take, no limit if it's not set
+    #[arg(long)]
+    max_memory_bytes: Option<u64>,
+
+    // What to do with the writes that don't fit in `max_memory_bytes`
+    #[arg(long, value_enum, default_value_t = EvictionPolicy::NoEviction)]
+    eviction_policy: EvictionPolicy,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EngineKind {
+    // Every entry in memory, rebuilt from the snapshot and the log on restart
+    Memory,
+    // Entries in an LSM tree next to the log, only the tail of the log is replayed on restart
+    Lsm,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DurabilityMode {
+    Always,
+    GroupCommit,
+    Interval,
+    Os,
+}
+
+impl Args {
+    fn durability(&self) -> Durability {
+        match self.durability {
+            DurabilityMode::Always => Durability::Always,
+            DurabilityMode::GroupCommit => Durability::GroupCommit {
+                window: Duration::from_millis(self.group_commit_window_ms),
+            },
+            DurabilityMode::Interval => Durability::Interval {
+                every: Duration::from_millis(self.fsync_interval_ms),
+            },
+            DurabilityMode::Os => Durability::Os,
+        }
+    }
+
+    // Opens the storage engine of the KV of the log in `log_directory`
+    fn open_engine(&self, log_directory: &str) -> Box<dyn StorageEngine> {
+        match self.storage_engine {
+            EngineKind::Memory => Box::new(MemoryEngine::new()),
+            EngineKind::Lsm => {
+                let options = LsmOptions {
+                    memtable_bytes: self.memtable_bytes,
+                    ..LsmOptions::default()
+                };
+                let engine = LsmEngine::open(format!("{log_directory}.lsm"), options).unwrap();
+                let levels = Arc::downgrade(&engine.levels());
+                let every = Duration::from_millis(self.compaction_interval_ms);
+
+                thread::spawn(move || compact_periodically(levels, every));
+                Box::new(engine)
+            }
+        }
+    }
+}
+
+fn parse_range(range: &str) -> Result<RangeInclusive<char>, String> {
+    let mut chars = range.chars();
+
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some(start), Some('-'), Some(end), None) if start <= end => Ok(start..=end),
+        _ => Err(format!("expected a range like a-h, got {range}")),
+    }
+}
+
+// Owners whose ranges the node replicated before, from the logs it keeps for them
+fn replicated_before(node_id: u8) -> Vec<String> {
+    let prefix = format!("log.{node_id}.");
+
+    fs::read_dir(".")
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        // Snapshots are files next to the log directories
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| !name.ends_with(".lsm") && !name.contains(".snapshot."))
+        .filter_map(|name| name.strip_prefix(&prefix).map(str::to_string))
+        .collect()
+}
+
+// Registers the node with the coordinator
+fn register(zk: &ZooKeeper, node: &Node) {
+    let path = format!("/nodes/node-{}", node.node_id);
+
+    loop {
+        match zk.create(
+            &path,
+            bincode::serialize(node).unwrap(),
+            Acl::open_unsafe().clone(),
+            CreateMode::Ephemeral,
+        ) {
+            // The session of a previous run of the node hasn't expired yet
+            Err(ZkError::NodeExists) => thread::sleep(Duration::from_millis(1000)),
+            result => {
+                result.unwrap();
+                return;
+            }
+        }
+    }
+}
+
+// Allocations published by the coordinator, if any, and a channel notified when they change
+fn watch_allocations(zk: &ZooKeeper) -> (Option<Vec<NamespaceAllocation>>, Receiver<()>) {
+    let (send, recv) = channel();
+    let watcher = move |_: WatchedEvent| {
+        let _ = send.send(());
+    };
+
+    let allocations = zk.exists_w("/allocations", watcher).unwrap().and_then(|_| {
+        let (binary, _) = zk.get_data("/allocations", false).ok()?;
+        bincode::deserialize(&binary).ok()
+    });
+
+    (allocations, recv)
+}
+
+// Waits for the coordinator to publish allocations that include the node
+fn wait_for_allocations(zk: &ZooKeeper, address: &str) -> Vec<NamespaceAllocation> {
+    loop {
+        let (allocations, changed) = watch_allocations(zk);
+
+        if let Some(allocations) = allocations {
+            if allocations.iter().any(|allocation| {
+                allocation.node == address
+                    || allocation.replicas.iter().any(|replica| replica == address)
+            }) {
+                return allocations;
+            }
+        }
+
+        println!("Waiting for the coordinator to allocate the ranges");
+        changed.recv().unwrap();
+    }
+}
+
+/*
+ * Follows the allocations the coordinator publishes. When the owner of a range the node replicates
+ * is gone, the coordinator might promote the node to own the range. The node then writes what it
+ * replicated of it into its own log, so that its replicas get it too, before accepting commands
+ * for it.
+ */
+fn follow_allocations(
+    zk: ZooKeeper,
+    args: Args,
+    mut allocations: Vec<NamespaceAllocation>,
+    kv: Arc<RwLock<KV>>,
+    command_log: Arc<RwLock<CommandLog>>,
+    replication: Arc<Replication>,
+    ranges: Arc<Ranges>,
+) {
+    let address = format!("localhost:{}", args.port);
+
+    loop {
+        let (published, changed) = watch_allocations(&zk);
+
+        if let Some(published) = published.filter(|published| *published != allocations) {
+            println!("Allocations changed {:?}", published);
+
+            let mut owned: Vec<NamespaceAllocation> = published
+                .iter()
+                .filter(|allocation| allocation.node == address)
+                .cloned()
+                .collect();
+            let mut replicas: Vec<String> = owned
+                .iter()
+                .flat_map(|allocation| allocation.replicas.clone())
+                .collect();
+            replicas.sort();
+            replicas.dedup();
+            replication.expect(replicas);
+
+            // Ranges that couldn't be taken over, with their previous owner
+            let mut not_taken = Vec::new();
+            for allocation in &owned {
+                let previous = allocations
+                    .iter()
+                    .find(|previous| previous.range == allocation.range);
+
+                if let Some(previous) = previous.filter(|previous| previous.node != address) {
+                    if let Err(e) =
+                        take_over(&previous.node, &kv, &command_log, &replication, &ranges)
+                    {
+                        println!("Can't take over the range of {}: {}", previous.node, e);
+                        not_taken.push((allocation.range.clone(), previous.node.clone()));
+                    }
+                }
+            }
+            owned.retain(|allocation| {
+                !not_taken
+                    .iter()
+                    .any(|(range, _)| *range == allocation.range)
+            });
+
+            *ranges.owned.write().unwrap() = owned;
+            follow_owners(&published, &address, &args, &ranges);
+            allocations = published;
+
+            // They are still answered as not owned, and taken over again with the next allocations
+            for (range, previous) in not_taken {
+                for allocation in allocations.iter_mut() {
+                    if allocation.range == range {
+                        allocation.node = previous.clone();
+                    }
+                }
+            }
+        }
+
+        changed.recv().unwrap();
+    }
+}
+
+// Replicates the owners that have the node as a replica, and only them
+fn follow_owners(allocations: &[NamespaceAllocation], address: &str, args: &Args, ranges: &Ranges) {
+    let owners: Vec<&String> = allocations
+        .iter()
+        .filter(|allocation| allocation.replicas.iter().any(|replica| replica == address))
+        .map(|allocation| &allocation.node)
+        .collect();
+
+    let (stopped, new_owners): (Vec<(String, Replicating)>, Vec<&String>) = {
+        let mut replicating = ranges.replicating.lock().unwrap();
+        let stopped_owners: Vec<String> = replicating
+            .keys()
+            .filter(|owner| !owners.contains(owner))
+            .cloned()
+            .collect();
+        let stopped = stopped_owners
+            .into_iter()
+            .filter_map(|owner| replicating.remove(&owner).map(|stopped| (owner, stopped)))
+            .collect();
+
+        let new_owners = owners
+            .into_iter()
+            .filter(|owner| !replicating.contains_key(*owner))
+            .collect();
+
+        (stopped, new_owners)
+    };
+
+    // The replicated KV is closed before the owner can be replicated again, which reopens its log
+    for (owner, replicating) in stopped {
+        println!("Stopped replicating {}", owner);
+        let _ = replicating.stream.shutdown(Shutdown::Both);
+        replicating.thread.join().unwrap();
+    }
+
+    // Connecting can take a while, the owners already replicated are still answered for meanwhile
+    for owner in new_owners {
+        println!("Replicating {}", owner);
+        let replicating = replicate(owner, args);
+
+        ranges
+            .replicating
+            .lock()
+            .unwrap()
+            .insert(owner.clone(), replicating);
+    }
+}
+
+// Connects to `owner` to replicate its range, from where the local log of the range left off
+fn replicate(owner: &str, args: &Args) -> Replicating {
+    let stream = open_replica_stream(owner);
+    let link = stream.try_clone().unwrap();
+    let (reader, mut writer) = protocol::open(stream, args.protocol).unwrap();
+    let replica_command_log = CommandLog::new(
+        format!("log.{}.{owner}", args.id),
+        args.durability(),
+        args.segment_bytes,
+    );
+    let replica_kv = KV::init_from_log(
+        args.open_engine(replica_command_log.directory()),
+        &replica_command_log,
+    );
+
+    writer
+        .write_message(&Message::Connect(Connect {
+            // TODO: do we need to send the address of the peer. The KV could get it
+            // from the connection
+            from: format!("localhost:{}", args.port),
+            sequence: replica_command_log.sequence(),
+        }))
+        .unwrap();
+
+    let sequence = Arc::new(AtomicUsize::new(replica_command_log.sequence()));
+    let snapshot_interval = Duration::from_secs(args.snapshot_interval_secs);
+    let thread = {
+        let sequence = sequence.clone();
+
+        thread::spawn(move || {
+            handle_replica_stream(
+                reader,
+                writer,
+                replica_kv,
+                replica_command_log,
+                sequence,
+                snapshot_interval,
+            )
+        })
+    };
+
+    Replicating {
+        stream: link,
+        sequence,
+        thread,
+    }
+}
+
+/*
+ * Writes the entries replicated from `owner` into the KV, as commands of the log of the node. The
+ * node is read-only until its replicas catch up, but they get the entries from the log then, so
+ * the writes don't wait for them. Fails if the node doesn't replicate `owner`, the range must not
+ * be taken without its entries.
+ */
+fn take_over(
+    owner: &str,
+    kv: &RwLock<KV>,
+    command_log: &RwLock<CommandLog>,
+    replication: &Replication,
+    ranges: &Ranges,
+) -> IOResult<()> {
+    // Entries are written in batches of this many
+    const BATCH_KEYS: usize = 1000;
+
+    let Some(replicating) = ranges.replicating.lock().unwrap().remove(owner) else {
+        return Err(IOError::new(
+            ErrorKind::NotFound,
+            format!("the node doesn't replicate {}", owner),
+        ));
+    };
+
+    // The owner is gone, but the connection to it might not have noticed yet
+    let _ = replicating.stream.shutdown(Shutdown::Both);
+    let mut replicated = replicating.thread.join().unwrap();
+    replicated.sweep(unix_time_ms());
+
+    let entries = replicated.snapshot(0).entries;
+    println!("Taking over {} keys of {}", entries.len(), owner);
+
+    let mut entries = entries.into_iter().peekable();
+    while entries.peek().is_some() {
+        let command = Command::TakeOver {
+            entries: entries.by_ref().take(BATCH_KEYS).collect(),
+        };
+        let (sequence, log_sync) = {
+            let mut kv = kv.write().unwrap();
+            // The entries are written even past the memory limit, otherwise they would be lost
+            let eviction = kv.make_room(&command).unwrap_or_else(|_| {
+                println!("Taking over the range of {} past the memory limit", owner);
+                None
+            });
+
+            let mut command_log = command_log.write().unwrap();
+            let (sequence, _) =
+                append_command(&mut kv, &mut command_log, replication, eviction, &command);
+
+            (sequence, command_log.log_sync())
+        };
+
+        log_sync.wait(sequence);
+    }
+
+    Ok(())
+}
+
+fn open_replica_stream(address: &str) -> TcpStream {
+    loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => return stream,
+            Err(_) => thread::sleep(Duration::from_millis(4000)),
+        }
+    }
+}
+
+struct LoggingWatcher;
+impl Watcher for LoggingWatcher {
+    fn handle(&self, e: WatchedEvent) {
+        // TODO: use the info! macro
+        println!("{:?}", e)
+    }
+}
+
+pub fn main() {
+    let args = Args::parse();
+    let node_id = args.id;
+    let durability = args.durability();
+    let segment_bytes = args.segment_bytes;
+    let snapshot_interval = Duration::from_secs(args.snapshot_interval_secs);
+    let port = args.port.clone();
+
+    let listening_address = format!("localhost:{port}");
+    println!("Listening at {}", listening_address);
+    let listener = TcpListener::bind(&listening_address).unwrap();
+    let command_log = Arc::new(RwLock::new(CommandLog::new(
+        format!("log.{node_id}"),
+        durability,
+        segment_bytes,
+    )));
+    let kv = {
+        let command_log = command_log.read().unwrap();
+        let engine = args.open_engine(command_log.directory());
+        let mut kv = KV::init_from_log(engine, &command_log);
+
+        kv.limit_memory(args.eviction_policy, args.max_memory_bytes);
+        Arc::new(RwLock::new(kv))
+    };
+    {
+        let kv = kv.clone();
+        let command_log = command_log.clone();
+        thread::spawn(move || snapshot_periodically(kv, command_log, snapshot_interval));
+    }
+    {
+        let kv = kv.clone();
+        let every = Duration::from_millis(args.expiry_sweep_interval_ms);
+        thread::spawn(move || sweep_expired_periodically(kv, every));
+    }
+
+    let zk = ZooKeeper::connect("localhost:2181", Duration::from_secs(15), LoggingWatcher).unwrap();
+
+    register(
+        &zk,
+        &Node {
+            node_id,
+            address: listening_address.clone(),
+            range: args.range.clone(),
+            replicating: replicated_before(node_id),
+        },
+    );
+
+    let allocations = wait_for_allocations(&zk, &listening_address);
+    let owned: Vec<NamespaceAllocation> = allocations
+        .iter()
+        .filter(|allocation| allocation.node == listening_address)
+        .cloned()
+        .collect();
+    // Nodes that replicate the ranges of this node
+    let replicas: Vec<String> = owned
+        .iter()
+        .flat_map(|allocation| allocation.replicas.clone())
+        .collect();
+    // Owners of the ranges this node replicates
+    let replicated: Vec<String> = allocations
+        .iter()
+        .filter(|allocation| allocation.replicas.contains(&listening_address))
+        .map(|allocation| allocation.node.clone())
+        .collect();
+    let ranges = Arc::new(Ranges {
+        owned: RwLock::new(owned),
+        replicating: Mutex::new(HashMap::new()),
+    });
+
+    println!("Replicas {:?}, replicating {:?}", replicas, replicated);
+
+    let replication = Arc::new(Replication::new(
+        replicas.clone(),
+        args.consistency,
+        Duration::from_millis(args.replication_timeout_ms),
+    ));
+
+    for owner in replicated {
+        let replicating = replicate(&owner, &args);
+
+        ranges
+            .replicating
+            .lock()
+            .unwrap()
+            .insert(owner, replicating);
+    }
+
+    {
+        let args = args.clone();
+        let kv = kv.clone();
+        let command_log = command_log.clone();
+        let replication = replication.clone();
+        let ranges = ranges.clone();
+
+        thread::spawn(move || {
+            follow_allocations(zk, args, allocations, kv, command_log, replication, ranges)
+        });
+    }
+
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+        println!(
+            "KV server: Accepted connection {}",
+            stream.peer_addr().unwrap()
+        );
+        /*
+         * Clone the Arc to increase the refererence count and also
+         * let the thread closure move this clone
          */
         let kv = kv.clone();
         let command_log = command_log.clone();
-        let replication_peers = replication_peers.clone();
+        let replication = replication.clone();
+        let ranges = ranges.clone();
 
         thread::spawn(move || {
             handle_stream(
                 stream.try_clone().unwrap(),
                 kv,
                 command_log,
-                replication_peers,
+                replication,
+                ranges,
             );
             println!("Thread exiting {}", stream.peer_addr().unwrap());
         });
@@ -505,3 +1971,838 @@ pub fn main() {
 
     // handle.join();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_command, catch_up_replica, handle_stream, prefix_end, take_over, Consistency, Ranges,
+        Replicating, Replication, ReplicationPeer, WriteOutcome, KV,
+    };
+    use rustkv::command_log::{CommandLog, Durability};
+    use rustkv::eviction::EvictionPolicy;
+    use rustkv::lsm::{LsmEngine, LsmOptions};
+    use rustkv::protocol::{open, MessageReader, MessageWriter, Protocol};
+    use rustkv::storage::MemoryEngine;
+    use rustkv::{
+        unix_time_ms, Command, ConnectOk, ErrorCode, Message, NamespaceAllocation, ReplicationAck,
+        ReplicationCommand, Request, Response, ResponseBody,
+    };
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_expired_keys_are_hidden_until_swept() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        let now = unix_time_ms();
+
+        kv.apply(
+            &Command::SetExpiring {
+                key: b"expired".to_vec(),
+                value: b"a".to_vec(),
+                expires_at: now - 1,
+            },
+            1,
+        );
+        kv.apply(
+            &Command::SetWithTtl {
+                key: b"alive".to_vec(),
+                value: b"b".to_vec(),
+                ttl_ms: 60_000,
+            }
+            .with_deadline(now),
+            2,
+        );
+        kv.apply(
+            &Command::ExpireAt {
+                key: b"missing".to_vec(),
+                expires_at: now + 60_000,
+            },
+            3,
+        );
+
+        assert_eq!(kv.get(b"expired"), None);
+        assert_eq!(kv.ttl(b"expired"), None);
+        assert_eq!(kv.get(b"alive").unwrap().value, b"b".to_vec());
+        assert!(kv.ttl(b"alive").unwrap().unwrap() <= 60_000);
+        assert_eq!(kv.engine.get(b"missing"), None);
+
+        assert_eq!(kv.sweep(unix_time_ms()), 1);
+        assert_eq!(kv.engine.get(b"expired"), None);
+
+        kv.apply(
+            &Command::Persist {
+                key: b"alive".to_vec(),
+            },
+            4,
+        );
+        assert_eq!(kv.ttl(b"alive"), Some(None));
+    }
+
+    #[test]
+    fn test_conditional_writes_resolve_to_plain_writes() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        kv.set(b"key".to_vec(), b"old".to_vec(), None, 1);
+
+        let compare_and_set = |expected: &[u8]| Command::CompareAndSet {
+            key: b"key".to_vec(),
+            expected: expected.to_vec(),
+            new: b"new".to_vec(),
+        };
+
+        assert!(kv.resolve(compare_and_set(b"other")).is_err());
+        assert_eq!(
+            kv.resolve(compare_and_set(b"old")).ok(),
+            Some(Command::Set {
+                key: b"key".to_vec(),
+                value: b"new".to_vec()
+            })
+        );
+        assert!(kv
+            .resolve(Command::SetIfAbsent {
+                key: b"key".to_vec(),
+                value: b"value".to_vec()
+            })
+            .is_err());
+        assert_eq!(
+            kv.resolve(Command::DeleteIfEquals {
+                key: b"key".to_vec(),
+                expected: b"old".to_vec()
+            })
+            .ok(),
+            Some(Command::Delete {
+                key: b"key".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn test_writes_bump_the_version_of_the_key() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        let set = |value: &[u8]| Command::Set {
+            key: b"key".to_vec(),
+            value: value.to_vec(),
+        };
+
+        kv.apply(&set(b"a"), 3);
+        assert_eq!(kv.get(b"key").unwrap().version, 3);
+
+        let stale = Command::SetIfVersion {
+            key: b"key".to_vec(),
+            value: b"b".to_vec(),
+            if_version: 2,
+        };
+        assert!(kv.resolve(stale).is_err());
+
+        let current = Command::SetIfVersion {
+            key: b"key".to_vec(),
+            value: b"b".to_vec(),
+            if_version: 3,
+        };
+        let command = kv.resolve(current).ok().unwrap();
+        assert_eq!(command, set(b"b"));
+
+        kv.apply(&command, 7);
+        assert_eq!(kv.get(b"key").unwrap().version, 7);
+        assert!(kv
+            .resolve(Command::DeleteIfVersion {
+                key: b"key".to_vec(),
+                if_version: 3
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_increment_resolves_to_the_new_value() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+
+        let (command, value) = kv.increment(b"counter".to_vec(), 5).ok().unwrap();
+        assert_eq!(value, 5);
+        assert_eq!(
+            command,
+            Command::Set {
+                key: b"counter".to_vec(),
+                value: b"5".to_vec()
+            }
+        );
+        kv.apply(&command, 1);
+
+        assert_eq!(kv.increment(b"counter".to_vec(), -7).ok().unwrap().1, -2);
+        assert_eq!(
+            kv.increment(b"counter".to_vec(), i64::MAX - 5)
+                .ok()
+                .unwrap()
+                .1,
+            i64::MAX
+        );
+        assert!(matches!(
+            kv.increment(b"counter".to_vec(), i64::MAX - 4),
+            Err(WriteOutcome::Overflow)
+        ));
+
+        kv.set(b"text".to_vec(), b"abc".to_vec(), None, 2);
+        assert!(matches!(
+            kv.increment(b"text".to_vec(), 1),
+            Err(WriteOutcome::NotAnInteger)
+        ));
+    }
+
+    #[test]
+    fn test_batch_writes_every_key_with_the_same_version() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        kv.set(
+            b"c".to_vec(),
+            b"3".to_vec(),
+            Some(unix_time_ms() + 60_000),
+            1,
+        );
+
+        kv.apply(
+            &Command::MultiSet {
+                entries: vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"2".to_vec()),
+                    (b"c".to_vec(), b"4".to_vec()),
+                ],
+            },
+            2,
+        );
+
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(kv.get(key).unwrap().version, 2);
+        }
+        assert_eq!(kv.ttl(b"c"), Some(None));
+
+        kv.apply(
+            &Command::MultiDelete {
+                keys: vec![b"a".to_vec(), b"missing".to_vec()],
+            },
+            3,
+        );
+
+        assert_eq!(kv.get(b"a"), None);
+        assert_eq!(kv.get(b"b").unwrap().value, b"2".to_vec());
+    }
+
+    #[test]
+    fn test_writes_past_the_limit_evict_the_least_recently_used_keys() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        for (sequence, key) in ["a", "b", "c"].into_iter().enumerate() {
+            kv.set(key.as_bytes().to_vec(), b"123".to_vec(), None, sequence + 1);
+        }
+
+        // Every key and value takes 4 bytes
+        kv.limit_memory(EvictionPolicy::AllKeysLru, Some(12));
+        kv.touch(b"a");
+
+        let set = |key: &[u8], value: &[u8]| Command::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        assert!(matches!(kv.make_room(&set(b"a", b"456")), Ok(None)));
+
+        match kv.make_room(&set(b"d", b"12345")) {
+            Ok(Some(Command::MultiDelete { keys })) => {
+                assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()])
+            }
+            _ => panic!("expected an eviction"),
+        }
+        assert_eq!(kv.info().evicted_keys, 2);
+
+        assert!(matches!(
+            kv.make_room(&set(b"d", b"1234567890123")),
+            Err(WriteOutcome::OutOfMemory)
+        ));
+
+        kv.limit_memory(EvictionPolicy::NoEviction, Some(12));
+        assert!(matches!(
+            kv.make_room(&set(b"d", b"1")),
+            Err(WriteOutcome::OutOfMemory)
+        ));
+        assert!(matches!(
+            kv.make_room(&Command::Delete { key: b"a".to_vec() }),
+            Ok(None)
+        ));
+        assert_eq!(kv.info().used_bytes, 12);
+    }
+
+    #[test]
+    fn test_keys_are_only_tracked_with_a_memory_limit() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        for (sequence, key) in ["a", "b"].into_iter().enumerate() {
+            kv.set(key.as_bytes().to_vec(), b"123".to_vec(), None, sequence + 1);
+        }
+        kv.touch(b"a");
+
+        assert_eq!(kv.eviction.lock().unwrap().keys(), 0);
+        assert_eq!((kv.info().keys, kv.info().used_bytes), (2, 8));
+
+        // The usage is still counted as the keys change
+        kv.set(b"a".to_vec(), b"12345".to_vec(), None, 3);
+        kv.set(b"c".to_vec(), b"1".to_vec(), None, 4);
+        kv.del(b"b");
+        kv.del(b"d");
+        assert_eq!((kv.info().keys, kv.info().used_bytes), (2, 8));
+
+        kv.limit_memory(EvictionPolicy::AllKeysLru, Some(100));
+        assert_eq!(kv.eviction.lock().unwrap().keys(), 2);
+        assert_eq!((kv.info().keys, kv.info().used_bytes), (2, 8));
+
+        kv.limit_memory(EvictionPolicy::NoEviction, None);
+        assert_eq!(kv.eviction.lock().unwrap().keys(), 0);
+        assert_eq!((kv.info().keys, kv.info().used_bytes), (2, 8));
+    }
+
+    #[test]
+    fn test_scan_pages_through_the_keys_in_order() {
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+        for (sequence, key) in ["b", "a", "d", "c", "e"].into_iter().enumerate() {
+            kv.set(key.as_bytes().to_vec(), b"v".to_vec(), None, sequence + 1);
+        }
+        kv.set(b"bb".to_vec(), b"v".to_vec(), Some(unix_time_ms() - 1), 6);
+
+        let keys = |entries: Vec<(Vec<u8>, _)>| {
+            entries
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let (entries, cursor) = kv.scan(b"b", Some(b"e"), 2, None);
+        assert_eq!(keys(entries), vec!["b", "c"]);
+        assert_eq!(cursor, Some(b"c".to_vec()));
+
+        let (entries, cursor) = kv.scan(b"b", Some(b"e"), 2, cursor.as_deref());
+        assert_eq!(keys(entries), vec!["d"]);
+        assert_eq!(cursor, None);
+
+        assert!(kv.scan(b"e", Some(b"b"), 2, None).0.is_empty());
+        assert_eq!(keys(kv.scan(b"", None, 10, None).0).len(), 5);
+
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[b'a', 0xff]), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(&[0xff]), None);
+    }
+
+    #[test]
+    fn test_lsm_engine_recovers_from_the_tail_of_the_log() {
+        let directory = std::env::temp_dir().join(format!("rustkv-kv-lsm-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let log_directory = directory.join("log").to_str().unwrap().to_string();
+        let lsm_directory = directory.join("lsm");
+        let set = |key: &str| Command::Set {
+            key: key.as_bytes().to_vec(),
+            value: b"v".to_vec(),
+        };
+
+        let mut command_log = CommandLog::new(log_directory.clone(), Durability::Os, 1024);
+        let engine = LsmEngine::open(&lsm_directory, LsmOptions::default()).unwrap();
+        let mut kv = KV::init_from_log(Box::new(engine), &command_log);
+
+        for key in ["a", "b"] {
+            let command = set(key);
+            kv.apply(&command, command_log.append(&command));
+        }
+        assert_eq!(kv.checkpoint(), Some(2));
+
+        let command = set("c");
+        kv.apply(&command, command_log.append(&command));
+        drop(kv);
+
+        let engine = LsmEngine::open(&lsm_directory, LsmOptions::default()).unwrap();
+        let kv = KV::init_from_log(Box::new(engine), &command_log);
+        for (key, version) in [(b"a", 1), (b"b", 2), (b"c", 3)] {
+            assert_eq!(kv.get(key).unwrap().version, version);
+        }
+    }
+
+    #[test]
+    fn test_take_over_writes_the_entries_while_the_node_is_read_only() {
+        let directory =
+            std::env::temp_dir().join(format!("rustkv-kv-take-over-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let log_directory = directory.join("log").to_str().unwrap().to_string();
+        let now = unix_time_ms();
+
+        let mut replicated = KV::new(Box::new(MemoryEngine::new()));
+        replicated.set(b"a".to_vec(), b"1".to_vec(), None, 7);
+        replicated.set(b"b".to_vec(), b"2".to_vec(), Some(now + 60_000), 9);
+        replicated.set(b"expired".to_vec(), b"3".to_vec(), Some(now - 1), 11);
+
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let ranges = Ranges {
+            owned: RwLock::new(Vec::new()),
+            replicating: Mutex::new(HashMap::from([(
+                "localhost:1".to_string(),
+                Replicating {
+                    stream: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+                    sequence: Arc::new(AtomicUsize::new(11)),
+                    thread: thread::spawn(move || replicated),
+                },
+            )])),
+        };
+        let kv = RwLock::new(KV::new(Box::new(MemoryEngine::new())));
+        let command_log = RwLock::new(CommandLog::new(log_directory.clone(), Durability::Os, 1024));
+        // The replica of the node is not connected yet
+        let replication = Replication::new(
+            vec!["localhost:2".to_string()],
+            Consistency::All,
+            Duration::from_millis(100),
+        );
+        assert!(replication.is_read_only());
+
+        take_over("localhost:1", &kv, &command_log, &replication, &ranges).unwrap();
+
+        assert!(ranges.replicating.lock().unwrap().is_empty());
+        let kv = kv.into_inner().unwrap();
+        // The entries get the version of the command that wrote them in this node
+        assert_eq!(kv.get(b"a").map(|entry| entry.version), Some(1));
+        assert_eq!(
+            kv.get(b"b").and_then(|entry| entry.expires_at),
+            Some(now + 60_000)
+        );
+        assert_eq!(kv.get(b"b").map(|entry| entry.version), Some(1));
+        assert!(kv.get(b"expired").is_none());
+
+        // Replicas and restarts get the same versions from the log
+        let command_log = command_log.into_inner().unwrap();
+        assert_eq!(command_log.sequence(), 1);
+        let restarted = KV::init_from_log(Box::new(MemoryEngine::new()), &command_log);
+        assert_eq!(restarted.get(b"a"), kv.get(b"a"));
+        assert_eq!(restarted.get(b"b"), kv.get(b"b"));
+
+        // No range is taken without its entries
+        assert!(take_over(
+            "localhost:1",
+            &RwLock::new(kv),
+            &RwLock::new(command_log),
+            &replication,
+            &ranges
+        )
+        .is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_writes_after_a_take_over_get_higher_versions() {
+        let directory =
+            std::env::temp_dir().join(format!("rustkv-kv-take-over-write-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let log_directory = directory.join("log").to_str().unwrap().to_string();
+
+        // The previous owner wrote far more commands than the node
+        let mut replicated = KV::new(Box::new(MemoryEngine::new()));
+        replicated.set(b"a".to_vec(), b"1".to_vec(), None, 100);
+
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let ranges = Ranges {
+            owned: RwLock::new(Vec::new()),
+            replicating: Mutex::new(HashMap::from([(
+                "localhost:1".to_string(),
+                Replicating {
+                    stream: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+                    sequence: Arc::new(AtomicUsize::new(100)),
+                    thread: thread::spawn(move || replicated),
+                },
+            )])),
+        };
+        let kv = RwLock::new(KV::new(Box::new(MemoryEngine::new())));
+        let command_log = RwLock::new(CommandLog::new(log_directory, Durability::Os, 1024));
+        let replication = Replication::new(vec![], Consistency::Async, Duration::from_millis(100));
+
+        take_over("localhost:1", &kv, &command_log, &replication, &ranges).unwrap();
+        let taken_over = kv.read().unwrap().get(b"a").unwrap().version;
+
+        // A write with the version the previous owner gave the key doesn't go through
+        let stale = Command::SetIfVersion {
+            key: b"a".to_vec(),
+            value: b"2".to_vec(),
+            if_version: 100,
+        };
+        assert!(matches!(
+            apply_command(&kv, &command_log, &replication, stale),
+            Ok(WriteOutcome::ConditionFailed)
+        ));
+
+        let set = Command::Set {
+            key: b"a".to_vec(),
+            value: b"3".to_vec(),
+        };
+        let Ok(WriteOutcome::Applied { sequence }) =
+            apply_command(&kv, &command_log, &replication, set)
+        else {
+            panic!("Expected the write to be applied");
+        };
+        assert!(sequence > taken_over);
+        assert_eq!(kv.read().unwrap().get(b"a").unwrap().version, sequence);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Both ends of a loopback connection
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        (server, client)
+    }
+
+    // Log with a command per key, applied to the KV
+    fn logged_kv(name: &str, segment_bytes: u64) -> (RwLock<KV>, RwLock<CommandLog>) {
+        let directory =
+            std::env::temp_dir().join(format!("rustkv-kv-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut command_log = CommandLog::new(
+            directory.to_str().unwrap().to_string(),
+            Durability::Os,
+            segment_bytes,
+        );
+        let mut kv = KV::new(Box::new(MemoryEngine::new()));
+
+        for key in ["a", "b", "c"] {
+            let command = Command::Set {
+                key: key.as_bytes().to_vec(),
+                value: b"v".to_vec(),
+            };
+            kv.apply(&command, command_log.append(&command));
+        }
+
+        (RwLock::new(kv), RwLock::new(command_log))
+    }
+
+    // A replica connected to the node. The other end of its connection has to be kept open.
+    fn fake_peer(name: &str, acked: usize, caught_up_at: usize) -> (ReplicationPeer, TcpStream) {
+        let (leader, replica) = connection();
+        let writer = MessageWriter::new(leader, Protocol::Bincode);
+
+        (
+            ReplicationPeer::new(name, writer, acked, caught_up_at),
+            replica,
+        )
+    }
+
+    // Replicas "a" and "b" connected to the node, neither of which acknowledged anything yet
+    fn replication_with_peers(
+        consistency: Consistency,
+        timeout_ms: u64,
+    ) -> (Replication, Vec<TcpStream>) {
+        let replication = Replication::new(
+            vec!["a".to_string(), "b".to_string()],
+            consistency,
+            Duration::from_millis(timeout_ms),
+        );
+        let mut connections = Vec::new();
+
+        for name in ["a", "b"] {
+            let (peer, connection) = fake_peer(name, 0, 0);
+            replication.register(peer);
+            connections.push(connection);
+        }
+
+        (replication, connections)
+    }
+
+    #[test]
+    fn test_a_replica_catches_up_from_the_log() {
+        let (kv, command_log) = logged_kv("catch-up-log", 1024);
+        let replication = Replication::new(
+            vec!["replica".to_string()],
+            Consistency::Async,
+            Duration::from_millis(100),
+        );
+        let (leader, replica) = connection();
+
+        catch_up_replica(
+            MessageWriter::new(leader, Protocol::Bincode),
+            "replica",
+            1,
+            &kv,
+            &command_log,
+            &replication,
+            &AtomicUsize::new(0),
+        )
+        .unwrap();
+
+        let mut reader = MessageReader::new(replica, Protocol::Bincode);
+        let mut next = || reader.next_message().unwrap().unwrap().unwrap();
+        assert!(matches!(
+            next(),
+            Message::ConnectOk(ConnectOk { snapshot: None })
+        ));
+        for expected in [2, 3] {
+            match next() {
+                Message::ReplicationCommand(ReplicationCommand { sequence, .. }) => {
+                    assert_eq!(sequence, expected)
+                }
+                message => panic!("expected a command, got {:?}", message),
+            }
+        }
+
+        // Registered, and caught up once it acknowledges the last command
+        assert!(replication.is_read_only());
+        replication.ack("replica", 3);
+        assert!(!replication.is_read_only());
+    }
+
+    #[test]
+    fn test_a_replica_catches_up_from_a_snapshot_once_the_log_is_compacted() {
+        // Every command gets its own segment
+        let (kv, command_log) = logged_kv("catch-up-snapshot", 1);
+        command_log.write().unwrap().compact(2);
+        let replication = Replication::new(
+            vec!["replica".to_string()],
+            Consistency::Async,
+            Duration::from_millis(100),
+        );
+        let (leader, replica) = connection();
+
+        // The replica acknowledged the snapshot before it was registered
+        catch_up_replica(
+            MessageWriter::new(leader, Protocol::Bincode),
+            "replica",
+            0,
+            &kv,
+            &command_log,
+            &replication,
+            &AtomicUsize::new(3),
+        )
+        .unwrap();
+
+        let mut reader = MessageReader::new(replica, Protocol::Bincode);
+        match reader.next_message().unwrap().unwrap().unwrap() {
+            Message::ConnectOk(ConnectOk {
+                snapshot: Some(snapshot),
+            }) => {
+                assert_eq!(snapshot.sequence, 3);
+                assert_eq!(snapshot.entries.len(), 3);
+            }
+            message => panic!("expected a snapshot, got {:?}", message),
+        }
+        assert!(!replication.is_read_only());
+    }
+
+    #[test]
+    fn test_consistency_one_waits_for_any_replica() {
+        let (replication, _connections) = replication_with_peers(Consistency::One, 5000);
+        let peers = vec!["a".to_string(), "b".to_string()];
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                replication.ack("b", 1);
+            });
+
+            assert!(replication.wait(&peers, 1));
+        });
+    }
+
+    #[test]
+    fn test_consistency_all_waits_for_every_replica() {
+        let (replication, _connections) = replication_with_peers(Consistency::All, 5000);
+        let peers = vec!["a".to_string(), "b".to_string()];
+        replication.ack("a", 1);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                replication.ack("b", 2);
+            });
+
+            // Only returns once "b" acknowledged too
+            assert!(replication.wait(&peers, 1));
+            assert_eq!(replication.peers.lock().unwrap()[1].acked, 2);
+        });
+    }
+
+    #[test]
+    fn test_writes_not_acknowledged_in_time_are_not_replicated() {
+        let (replication, _connections) = replication_with_peers(Consistency::All, 50);
+        let peers = vec!["a".to_string(), "b".to_string()];
+        replication.ack("a", 1);
+
+        let started = Instant::now();
+        assert!(!replication.wait(&peers, 1));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // Async writes don't wait at all
+        let (replication, _connections) = replication_with_peers(Consistency::Async, 50);
+        assert!(replication.wait(&peers, 1));
+    }
+
+    #[test]
+    fn test_a_replica_that_disconnects_fails_the_wait_right_away() {
+        let (replication, _connections) = replication_with_peers(Consistency::All, 5000);
+        let peers = vec!["a".to_string(), "b".to_string()];
+        let address = replication.peers.lock().unwrap()[1].address;
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                replication.unregister("b", address);
+            });
+
+            let started = Instant::now();
+            assert!(!replication.wait(&peers, 1));
+            assert!(started.elapsed() < Duration::from_secs(5));
+        });
+    }
+
+    #[test]
+    fn test_the_node_is_read_only_until_every_expected_replica_caught_up() {
+        let replication = Replication::new(
+            vec!["a".to_string(), "b".to_string()],
+            Consistency::Async,
+            Duration::from_secs(1),
+        );
+        // Neither replica connected
+        assert!(replication.is_read_only());
+
+        let (peer, _a) = fake_peer("a", 0, 0);
+        replication.register(peer);
+        // "b" is still missing
+        assert!(replication.is_read_only());
+
+        let (peer, _b) = fake_peer("b", 3, 5);
+        replication.register(peer);
+        // "b" is still catching up
+        assert!(replication.is_read_only());
+
+        replication.ack("b", 4);
+        assert!(replication.is_read_only());
+        replication.ack("b", 5);
+        assert!(!replication.is_read_only());
+
+        // The node no longer expects "b"
+        replication.expect(vec!["a".to_string()]);
+        assert_eq!(replication.peers.lock().unwrap().len(), 1);
+        replication.expect(vec![]);
+        assert!(!replication.is_read_only());
+    }
+
+    #[test]
+    fn test_a_replica_reconnecting_has_to_catch_up_again() {
+        let replication = Replication::new(
+            vec!["a".to_string()],
+            Consistency::Async,
+            Duration::from_secs(1),
+        );
+
+        let (peer, _first) = fake_peer("a", 5, 5);
+        let first_address = peer.address;
+        replication.register(peer);
+        assert!(!replication.is_read_only());
+
+        let (peer, _second) = fake_peer("a", 5, 8);
+        let second_address = peer.address;
+        replication.register(peer);
+        assert!(replication.is_read_only());
+        assert_eq!(replication.peers.lock().unwrap().len(), 1);
+
+        // The previous connection closing doesn't drop the new one
+        replication.unregister("a", first_address);
+        assert_eq!(replication.peers.lock().unwrap()[0].address, second_address);
+
+        replication.ack("a", 8);
+        assert!(!replication.is_read_only());
+
+        replication.unregister("a", second_address);
+        assert!(replication.is_read_only());
+    }
+
+    #[test]
+    fn test_messages_that_cant_be_handled_get_an_error_reply() {
+        let (kv, command_log) = logged_kv("error-replies", 1024);
+        let replication = Arc::new(Replication::new(
+            vec![],
+            Consistency::All,
+            Duration::from_millis(50),
+        ));
+        let ranges = Arc::new(Ranges {
+            owned: RwLock::new(vec![NamespaceAllocation {
+                node: "node-1".to_string(),
+                range: 'a'..='m',
+                replicas: vec![],
+            }]),
+            replicating: Mutex::new(HashMap::new()),
+        });
+        let (server, client) = connection();
+
+        let handler = {
+            let (kv, command_log) = (Arc::new(kv), Arc::new(command_log));
+            let (replication, ranges) = (replication.clone(), ranges.clone());
+            thread::spawn(move || handle_stream(server, kv, command_log, replication, ranges))
+        };
+        let (mut reader, mut writer) = open(client, Protocol::Json).unwrap();
+        let next_error = |reader: &mut MessageReader<TcpStream>| match reader
+            .next_message()
+            .unwrap()
+            .unwrap()
+            .unwrap()
+        {
+            Message::Error(error) => (None, error.code),
+            Message::Response(Response {
+                id,
+                body: ResponseBody::Error(error),
+            }) => (Some(id), error.code),
+            message => panic!("Unexpected message {:?}", message),
+        };
+        let set = |id: u64, key: &str| {
+            Message::Command(Request {
+                id,
+                command: Command::Set {
+                    key: key.as_bytes().to_vec(),
+                    value: b"v".to_vec(),
+                },
+            })
+        };
+
+        // Frames that are not a message have no request to reply to
+        writer.get_mut().write_all(b"{\"Command\"\n").unwrap();
+        assert_eq!(next_error(&mut reader), (None, ErrorCode::Malformed));
+        writer.get_mut().write_all(b"{\"Foo\":1}\n").unwrap();
+        assert_eq!(next_error(&mut reader), (None, ErrorCode::UnknownCommand));
+
+        // Only replicas acknowledge commands
+        writer
+            .write_message(&Message::ReplicationAck(ReplicationAck { sequence: 1 }))
+            .unwrap();
+        assert_eq!(
+            next_error(&mut reader),
+            (None, ErrorCode::UnexpectedMessage)
+        );
+
+        writer.write_message(&set(1, "z")).unwrap();
+        assert_eq!(next_error(&mut reader), (Some(1), ErrorCode::WrongOwner));
+
+        writer
+            .write_message(&Message::Command(Request {
+                id: 2,
+                command: Command::TakeOver { entries: vec![] },
+            }))
+            .unwrap();
+        assert_eq!(
+            next_error(&mut reader),
+            (Some(2), ErrorCode::UnexpectedMessage)
+        );
+
+        // A replica that never acknowledges the write
+        let (peer, _replica) = fake_peer("node-2", 0, 0);
+        replication.register(peer);
+        writer.write_message(&set(3, "a")).unwrap();
+        assert_eq!(next_error(&mut reader), (Some(3), ErrorCode::NotReplicated));
+
+        // A replica that is not connected
+        replication.expect(vec!["node-3".to_string()]);
+        writer.write_message(&set(4, "a")).unwrap();
+        assert_eq!(next_error(&mut reader), (Some(4), ErrorCode::ReadOnly));
+
+        drop(writer);
+        drop(reader);
+        handler.join().unwrap();
+    }
+}
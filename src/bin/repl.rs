@@ -1,27 +1,130 @@
 use easy_repl::{command, CommandStatus, Repl};
-use rustkv::{Command, Message, NamespaceAllocation};
+use rustkv::client::{self, Client};
+use rustkv::protocol::Protocol;
+use rustkv::{Command, Entry, NamespaceAllocation, ResponseBody};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::Result as IOResult;
+use std::sync::mpsc::channel;
 use std::time::Duration;
-use std::{
-    cell::RefCell,
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
-    ops::RangeInclusive,
-};
-use zookeeper::{Acl, CreateMode, WatchedEvent, Watcher, ZooKeeper};
-
-fn select_key_owner(key: &str, owners: &HashMap<String, RangeInclusive<char>>) -> Option<String> {
-    match key.chars().next() {
-        None => None,
-        Some(char) => {
-            for (owner, range) in owners.iter() {
-                if range.contains(&char) {
-                    return Some(owner.clone());
+use zookeeper::{AddWatchMode, WatchedEvent, Watcher, ZooKeeper};
+
+fn select_key_owner(key: &str, allocations: &[NamespaceAllocation]) -> Option<String> {
+    allocations
+        .iter()
+        .find(|allocation| allocation.contains(key.as_bytes()))
+        .map(|allocation| allocation.node.clone())
+}
+
+/*
+ * Splits the items of a batch by the node that owns their key, keeping their order. Returns `None`
+ * if some key is not owned by any node.
+ */
+fn split_by_owner<T>(
+    items: Vec<T>,
+    key: impl Fn(&T) -> &str,
+    allocations: &[NamespaceAllocation],
+) -> Option<HashMap<String, Vec<T>>> {
+    let mut batches: HashMap<String, Vec<T>> = HashMap::new();
+
+    for item in items {
+        let owner = select_key_owner(key(&item), allocations)?;
+        batches.entry(owner).or_default().push(item);
+    }
+
+    Some(batches)
+}
+
+// Owners sorted by the first range of keys they own
+fn owners_in_key_order(allocations: &[NamespaceAllocation]) -> Vec<String> {
+    let mut allocations: Vec<_> = allocations.iter().collect();
+    allocations.sort_by_key(|allocation| *allocation.range.start());
+
+    let mut owners: Vec<String> = Vec::new();
+    for allocation in allocations {
+        if !owners.contains(&allocation.node) {
+            owners.push(allocation.node.clone());
+        }
+    }
+
+    owners
+}
+
+// Connection to `owner`, opened if there is none yet
+fn connected<'a>(
+    clients: &'a mut HashMap<String, Client>,
+    owner: &str,
+) -> IOResult<&'a mut Client> {
+    if !clients.contains_key(owner) {
+        let client = Client::connect(owner, Protocol::Bincode)?;
+        clients.insert(owner.to_string(), client);
+    }
+
+    Ok(clients.get_mut(owner).unwrap())
+}
+
+fn read_allocations(zk: &ZooKeeper) -> Option<Vec<NamespaceAllocation>> {
+    let (binary, _) = zk.get_data("/allocations", false).ok()?;
+
+    bincode::deserialize(&binary).ok()
+}
+
+fn print_entries(entries: Vec<(Vec<u8>, Entry)>) {
+    for (key, entry) in entries {
+        print!("{} = ", String::from_utf8_lossy(&key));
+        print_response(ResponseBody::Value {
+            value: entry.value,
+            version: entry.version,
+        });
+    }
+}
+
+fn print_response(body: ResponseBody) {
+    match body {
+        // Values are bytes, the repl only shows the ones that are text
+        ResponseBody::Value { value, version } => match String::from_utf8(value) {
+            Ok(value) => println!("{} (version {})", value, version),
+            Err(e) => println!("(binary) {:?} (version {})", e.into_bytes(), version),
+        },
+        ResponseBody::NotFound => println!("(not found)"),
+        ResponseBody::Ok { sequence } => println!("OK ({})", sequence),
+        ResponseBody::Ttl {
+            remaining_ms: Some(remaining_ms),
+        } => println!("{}ms", remaining_ms),
+        ResponseBody::Ttl { remaining_ms: None } => println!("(no expiry)"),
+        ResponseBody::ConditionFailed => println!("(condition failed)"),
+        ResponseBody::Counter { value, .. } => println!("{}", value),
+        ResponseBody::Values(entries) => {
+            for entry in entries {
+                match entry {
+                    Some(entry) => print_response(ResponseBody::Value {
+                        value: entry.value,
+                        version: entry.version,
+                    }),
+                    None => print_response(ResponseBody::NotFound),
                 }
             }
+        }
+        ResponseBody::Entries { entries, cursor } => {
+            print_entries(entries);
 
-            None
+            if let Some(cursor) = cursor {
+                println!("(more after {})", String::from_utf8_lossy(&cursor));
+            }
+        }
+        ResponseBody::Info(info) => {
+            println!("keys: {}", info.keys);
+            match info.max_bytes {
+                Some(max_bytes) => println!("memory: {}/{} bytes", info.used_bytes, max_bytes),
+                None => println!("memory: {} bytes (no limit)", info.used_bytes),
+            }
+            println!("evicted keys: {}", info.evicted_keys);
+        }
+        ResponseBody::ReplicatedSequence(Some(sequence)) => {
+            println!("replicated up to {}", sequence)
         }
+        ResponseBody::ReplicatedSequence(None) => println!("(not replicated)"),
+        ResponseBody::Error(error) => println!("ERROR {:?}: {}", error.code, error.message),
     }
 }
 
@@ -35,24 +138,124 @@ impl Watcher for LoggingWatcher {
 
 pub(crate) fn main() {
     let zk = ZooKeeper::connect("localhost:2181", Duration::from_secs(15), LoggingWatcher).unwrap();
-    let (allocations_binary, _) = zk.get_data("/allocations", false).unwrap();
-    let allocations =
-        bincode::deserialize::<Vec<NamespaceAllocation>>(&allocations_binary).unwrap();
 
-    // TODO: the connection might be refused if the server thread was scheduled later than the repl
-    // thread
-    let mut ownership = HashMap::new();
-    let streams: &RefCell<HashMap<String, RefCell<TcpStream>>> = &RefCell::new(HashMap::new());
+    // Watched before reading them, so that no change is missed in between
+    let (send, changed) = channel();
+    zk.add_watch(
+        "/allocations",
+        AddWatchMode::Persistent,
+        move |_: WatchedEvent| {
+            let _ = send.send(());
+        },
+    )
+    .unwrap();
 
-    for allocation in allocations {
-        ownership.insert(allocation.node.clone(), allocation.range);
-        streams.borrow_mut().insert(
-            allocation.node.to_string(),
-            RefCell::new(TcpStream::connect(allocation.node).unwrap()),
-        );
-    }
+    let ownership: &RefCell<Vec<NamespaceAllocation>> = &RefCell::new(Vec::new());
+    // Owners are connected to by the first command sent to them. A connection that fails is
+    // dropped, and opened again by the next command.
+    let clients: &RefCell<HashMap<String, Client>> = &RefCell::new(HashMap::new());
+
+    // Drops the connections to the nodes that no longer own anything
+    let route = |allocations: Vec<NamespaceAllocation>| {
+        clients.borrow_mut().retain(|node, _| {
+            allocations
+                .iter()
+                .any(|allocation| allocation.node == *node)
+        });
 
-    let ownership = &RefCell::new(ownership);
+        *ownership.borrow_mut() = allocations;
+    };
+    route(read_allocations(&zk).unwrap());
+
+    // Allocations to send the commands by, following the changes made by the coordinator
+    let routes = || {
+        if changed.try_iter().count() > 0 {
+            match read_allocations(&zk) {
+                Some(allocations) => {
+                    println!("Allocations changed {:?}", allocations);
+                    route(allocations);
+                }
+                None => println!("Allocations are gone, keeping the previous ones"),
+            }
+        }
+
+        ownership.borrow()
+    };
+
+    let request = |owner: &str, command: Command| {
+        let mut clients = clients.borrow_mut();
+
+        match connected(&mut clients, owner).and_then(|client| client.request(command)) {
+            Ok(response) => print_response(response.body),
+            Err(e) => {
+                clients.remove(owner);
+                println!("ERROR {}: {}", owner, e);
+            }
+        }
+    };
+
+    // Sends the batch of each owner before waiting for any response, so that the owners work on
+    // them at the same time
+    let request_batches = |batches: Vec<(String, Command)>| {
+        let mut clients = clients.borrow_mut();
+        let mut sent = Vec::new();
+
+        for (owner, command) in batches {
+            match connected(&mut clients, &owner).and_then(|client| client.send(command)) {
+                Ok(id) => sent.push((owner, id)),
+                Err(e) => {
+                    clients.remove(&owner);
+                    println!("{}:\nERROR {}", owner, e);
+                }
+            }
+        }
+
+        for (owner, id) in sent {
+            println!("{}:", owner);
+
+            match clients.get_mut(&owner).unwrap().receive() {
+                Ok(response) if response.id == id => print_response(response.body),
+                Ok(response) => {
+                    clients.remove(&owner);
+                    println!(
+                        "ERROR expected response to request {}, got {}",
+                        id, response.id
+                    );
+                }
+                Err(e) => {
+                    clients.remove(&owner);
+                    println!("ERROR {}", e);
+                }
+            }
+        }
+    };
+
+    // Scans every node, `page` builds the command for a limit and a cursor
+    let scan = |limit: usize, page: &dyn Fn(usize, Option<Vec<u8>>) -> Command| {
+        let owners = owners_in_key_order(&routes());
+        let mut clients = clients.borrow_mut();
+
+        for owner in &owners {
+            if let Err(e) = connected(&mut clients, owner) {
+                println!("ERROR {}: {}", owner, e);
+                return;
+            }
+        }
+
+        let nodes = clients
+            .iter_mut()
+            .filter(|(node, _)| owners.contains(node))
+            .map(|(_, client)| client);
+
+        match client::scan_nodes(nodes, limit, page) {
+            Ok(entries) => print_entries(entries),
+            // Which node failed is not known, the connections of all of them are opened again
+            Err(e) => {
+                clients.retain(|node, _| !owners.contains(node));
+                println!("ERROR {}", e);
+            }
+        }
+    };
 
     let mut repl = Repl::builder()
         .add(
@@ -60,15 +263,10 @@ pub(crate) fn main() {
             command! {
                 "Set a value",
                 (key: String, value: String) =>|key: String, value: String| {
-                    match select_key_owner(&key, &ownership.borrow()) {
+                    match select_key_owner(&key, &routes()) {
                         None => panic!("Unhandled key"),
                         Some(owner) => {
-                            let result_stream = streams.borrow();
-                            let stream = result_stream.get(&owner).unwrap();
-
-                            // I have to finish the string with a \n because the receiving end is expecting
-                            // a breakline terminated string
-                            stream.borrow_mut().write_all(format!("{}\n", serde_json::to_string(&Message::Command(Command::Set { key, value })).unwrap()).as_bytes()).unwrap();
+                            request(&owner, Command::Set { key: key.into_bytes(), value: value.into_bytes() });
 
                             Ok(CommandStatus::Done)
                         }
@@ -81,25 +279,106 @@ pub(crate) fn main() {
             command! {
                 "Get a value",
                 (key: String) => |key: String| {
-                    match select_key_owner(&key, &ownership.borrow()) {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Get { key: key.into_bytes() });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "DEL",
+            command! {
+                "Delete a value",
+                (key: String) => |key: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Delete { key: key.into_bytes() });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "SETEX",
+            command! {
+                "Set a value that expires after some seconds",
+                (key: String, value: String, seconds: u64) => |key: String, value: String, seconds: u64| {
+                    match select_key_owner(&key, &routes()) {
                         None => panic!("Unhandled key"),
                         Some(owner) => {
-                            // Have to split the borrow and getting the "owner" because
-                            // of temporary values in Rust.
-                            //
-                            // This document gives a short explanation of when are temporaries
-                            // introduced https://github.com/rust-lang/lang-team/blob/master/design-meeting-minutes/2023-03-15-temporary-lifetimes.md#when-are-temporaries-introduced
-                            let streams_borrow = streams.borrow();
-                            let stream = streams_borrow.get(&owner).unwrap();
+                            request(&owner, Command::SetWithTtl { key: key.into_bytes(), value: value.into_bytes(), ttl_ms: seconds * 1000 });
 
-                            stream.borrow_mut().write_all(format!("{}\n", serde_json::to_string(&Message::Command(Command::Get { key })).unwrap()).as_bytes()).unwrap();
-                            let mut reader = BufReader::new(stream.borrow_mut().try_clone().unwrap());
-                            let mut value = String::new();
-                            reader.read_line(&mut value).unwrap();
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "EXPIRE",
+            command! {
+                "Expire a key after some seconds",
+                (key: String, seconds: u64) => |key: String, seconds: u64| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Expire { key: key.into_bytes(), ttl_ms: seconds * 1000 });
 
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "PERSIST",
+            command! {
+                "Remove the expiration of a key",
+                (key: String) => |key: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Persist { key: key.into_bytes() });
 
-                            println!("{}", value);
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "TTL",
+            command! {
+                "Get the time left until a key expires",
+                (key: String) => |key: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Ttl { key: key.into_bytes() });
 
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "CAS",
+            command! {
+                "Set a value if the current one is the expected",
+                (key: String, expected: String, new: String) => |key: String, expected: String, new: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::CompareAndSet { key: key.into_bytes(), expected: expected.into_bytes(), new: new.into_bytes() });
 
                             Ok(CommandStatus::Done)
                         }
@@ -108,18 +387,182 @@ pub(crate) fn main() {
             },
         )
         .add(
-            "DEL",
+            "SETNX",
             command! {
-                "Delete a value",
+                "Set a value if the key doesn't exist",
+                (key: String, value: String) => |key: String, value: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::SetIfAbsent { key: key.into_bytes(), value: value.into_bytes() });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "DELEQ",
+            command! {
+                "Delete a value if it's the expected",
+                (key: String, expected: String) => |key: String, expected: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::DeleteIfEquals { key: key.into_bytes(), expected: expected.into_bytes() });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "SETV",
+            command! {
+                "Set a value if the key is still at the given version",
+                (key: String, value: String, version: usize) => |key: String, value: String, version: usize| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::SetIfVersion { key: key.into_bytes(), value: value.into_bytes(), if_version: version });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "DELV",
+            command! {
+                "Delete a value if the key is still at the given version",
+                (key: String, version: usize) => |key: String, version: usize| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::DeleteIfVersion { key: key.into_bytes(), if_version: version });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "INCR",
+            command! {
+                "Increment a counter",
+                (key: String) => |key: String| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Increment { key: key.into_bytes(), delta: 1 });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "DECR",
+            command! {
+                "Decrement a counter",
                 (key: String) => |key: String| {
-                    match select_key_owner(&key, &ownership.borrow()) {
+                    match select_key_owner(&key, &routes()) {
                         None => panic!("Unhandled key"),
                         Some(owner) => {
-                            let streams_borrow = streams.borrow();
-                            let stream = streams_borrow.get(&owner).unwrap();
+                            request(&owner, Command::Increment { key: key.into_bytes(), delta: -1 });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "INCRBY",
+            command! {
+                "Add to a counter",
+                (key: String, delta: i64) => |key: String, delta: i64| {
+                    match select_key_owner(&key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(owner) => {
+                            request(&owner, Command::Increment { key: key.into_bytes(), delta });
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "MGET",
+            command! {
+                "Get the values of comma separated keys",
+                (keys: String) => |keys: String| {
+                    let keys = keys.split(',').map(str::to_string).collect();
+
+                    match split_by_owner(keys, |key| key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(batches) => {
+                            request_batches(batches.into_iter().map(|(owner, keys)| {
+                                let keys = keys.into_iter().map(String::into_bytes).collect();
+                                (owner, Command::MultiGet { keys })
+                            }).collect());
+
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "MSET",
+            command! {
+                "Set comma separated key=value pairs",
+                (entries: String) => |entries: String| {
+                    let entries = entries
+                        .split(',')
+                        .map(|entry| match entry.split_once('=') {
+                            Some((key, value)) => (key.to_string(), value.to_string()),
+                            None => (entry.to_string(), String::new()),
+                        })
+                        .collect();
+
+                    match split_by_owner(entries, |(key, _)| key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(batches) => {
+                            request_batches(batches.into_iter().map(|(owner, entries)| {
+                                let entries = entries
+                                    .into_iter()
+                                    .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+                                    .collect();
+                                (owner, Command::MultiSet { entries })
+                            }).collect());
 
-                            stream.borrow_mut().write_all(format!("{}\n", serde_json::to_string(&Message::Command(Command::Delete { key })).unwrap()).as_bytes()).unwrap();
+                            Ok(CommandStatus::Done)
+                        }
+                    }
+                }
+            },
+        )
+        .add(
+            "MDEL",
+            command! {
+                "Delete comma separated keys",
+                (keys: String) => |keys: String| {
+                    let keys = keys.split(',').map(str::to_string).collect();
 
+                    match split_by_owner(keys, |key| key, &routes()) {
+                        None => panic!("Unhandled key"),
+                        Some(batches) => {
+                            request_batches(batches.into_iter().map(|(owner, keys)| {
+                                let keys = keys.into_iter().map(String::into_bytes).collect();
+                                (owner, Command::MultiDelete { keys })
+                            }).collect());
 
                             Ok(CommandStatus::Done)
                         }
@@ -127,6 +570,51 @@ pub(crate) fn main() {
                 }
             },
         )
+        .add(
+            "SCAN",
+            command! {
+                "List up to limit keys from start to end (exclusive, * for no end)",
+                (start: String, end: String, limit: usize) => |start: String, end: String, limit: usize| {
+                    let end = (end != "*").then(|| end.into_bytes());
+
+                    scan(limit, &|limit, cursor| Command::Scan {
+                        start: start.clone().into_bytes(),
+                        end: end.clone(),
+                        limit,
+                        cursor,
+                    });
+
+                    Ok(CommandStatus::Done)
+                }
+            },
+        )
+        .add(
+            "PREFIX",
+            command! {
+                "List up to limit keys that start with a prefix",
+                (prefix: String, limit: usize) => |prefix: String, limit: usize| {
+                    scan(limit, &|limit, cursor| Command::PrefixScan {
+                        prefix: prefix.clone().into_bytes(),
+                        limit,
+                        cursor,
+                    });
+
+                    Ok(CommandStatus::Done)
+                }
+            },
+        )
+        .add(
+            "INFO",
+            command! {
+                "Show the memory used by every node",
+                () => || {
+                    let owners = owners_in_key_order(&routes());
+                    request_batches(owners.into_iter().map(|owner| (owner, Command::Info)).collect());
+
+                    Ok(CommandStatus::Done)
+                }
+            },
+        )
         .build()
         .expect("Failed to create repl");
 
@@ -135,14 +623,27 @@ pub(crate) fn main() {
 
 #[cfg(test)]
 mod tests {
+    use rustkv::protocol::accept;
+    use rustkv::NamespaceAllocation;
     use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::ops::RangeInclusive;
+    use std::thread;
+
+    fn allocation(node: &str, range: RangeInclusive<char>) -> NamespaceAllocation {
+        NamespaceAllocation {
+            node: node.to_string(),
+            range,
+            replicas: vec![],
+        }
+    }
 
     #[test]
     fn test_correct_routing() {
-        let mut key_owners = HashMap::new();
-
-        key_owners.insert("owner-1".to_string(), 'a'..='p');
-        key_owners.insert("owner-2".to_string(), 'q'..='z');
+        let key_owners = vec![
+            allocation("owner-1", 'a'..='p'),
+            allocation("owner-2", 'q'..='z'),
+        ];
 
         assert_eq!(
             super::select_key_owner("abc", &key_owners),
@@ -161,4 +662,64 @@ mod tests {
             Some("owner-2".to_string())
         );
     }
+
+    #[test]
+    fn test_owners_are_sorted_by_their_range() {
+        let key_owners = vec![
+            allocation("owner-2", 'n'..='z'),
+            allocation("owner-1", 'a'..='m'),
+            allocation("owner-0", '0'..='9'),
+        ];
+
+        assert_eq!(
+            super::owners_in_key_order(&key_owners),
+            vec!["owner-0", "owner-1", "owner-2"]
+        );
+
+        // After taking over the range of another node
+        let key_owners = vec![
+            allocation("owner-2", 'n'..='z'),
+            allocation("owner-2", 'a'..='g'),
+            allocation("owner-1", 'h'..='m'),
+        ];
+
+        assert_eq!(
+            super::owners_in_key_order(&key_owners),
+            vec!["owner-2", "owner-1"]
+        );
+    }
+
+    #[test]
+    fn test_batch_is_split_by_owner() {
+        let key_owners = vec![
+            allocation("owner-1", 'a'..='p'),
+            allocation("owner-2", 'q'..='z'),
+        ];
+
+        let keys = vec!["abc", "zz", "p", "qr"];
+        let batches = super::split_by_owner(keys, |key| key, &key_owners).unwrap();
+
+        assert_eq!(batches["owner-1"], vec!["abc", "p"]);
+        assert_eq!(batches["owner-2"], vec!["zz", "qr"]);
+        assert!(super::split_by_owner(vec!["abc", "A"], |key| key, &key_owners).is_none());
+    }
+    #[test]
+    fn test_owners_are_connected_to_when_first_used() {
+        let mut clients = HashMap::new();
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let owner = listener.local_addr().unwrap().to_string();
+        let node = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            accept(stream).unwrap()
+        });
+
+        super::connected(&mut clients, &owner).unwrap();
+        assert!(clients.contains_key(&owner));
+
+        // The node is gone
+        drop(node.join().unwrap());
+        clients.clear();
+        assert!(super::connected(&mut clients, &owner).is_err());
+        assert!(clients.is_empty());
+    }
 }
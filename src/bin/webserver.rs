@@ -1,19 +1,129 @@
 use regex::Regex;
-use rustkv::Command;
+use rustkv::client::{self, Client};
+use rustkv::protocol::Protocol;
+use rustkv::{Command, Entry, NamespaceAllocation, ResponseBody};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result as IOResult, Write};
 use std::net::TcpListener;
-use std::{
-    cell::RefCell,
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
-};
+use std::time::Duration;
+use zookeeper::{WatchedEvent, Watcher, ZooKeeper};
+
+struct LoggingWatcher;
+impl Watcher for LoggingWatcher {
+    fn handle(&self, e: WatchedEvent) {
+        println!("{:?}", e)
+    }
+}
+
+// One `key=value` line per entry
+fn entries_contents(entries: Vec<(Vec<u8>, Entry)>) -> Vec<u8> {
+    entries
+        .into_iter()
+        .map(|(mut key, entry)| {
+            key.push(b'=');
+            key.extend(entry.value);
+            key
+        })
+        .collect::<Vec<_>>()
+        .join(&b'\n')
+}
+
+/*
+ * Gets the keys from the nodes that own them, `nodes` being the connections to the `owners`. The
+ * batches of every node are sent before waiting for any of them. Returns the entries in the order
+ * of the keys, with `None` for the keys that are not found or not owned by any node.
+ */
+fn multi_get(
+    nodes: &mut [Client],
+    owners: &[String],
+    allocations: &[NamespaceAllocation],
+    keys: Vec<Vec<u8>>,
+) -> IOResult<Vec<Option<Entry>>> {
+    let mut entries = vec![None; keys.len()];
+    // Positions of the keys sent to each node
+    let mut positions: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    let mut batches: Vec<Vec<Vec<u8>>> = vec![Vec::new(); nodes.len()];
+
+    for (position, key) in keys.into_iter().enumerate() {
+        let owner = allocations
+            .iter()
+            .find(|allocation| allocation.contains(&key))
+            .and_then(|allocation| owners.iter().position(|owner| *owner == allocation.node));
+
+        if let Some(owner) = owner {
+            positions[owner].push(position);
+            batches[owner].push(key);
+        }
+    }
+
+    let mut sent = Vec::new();
+    for (node, keys) in batches.into_iter().enumerate() {
+        if !keys.is_empty() {
+            sent.push((node, nodes[node].send(Command::MultiGet { keys })?));
+        }
+    }
+
+    for (node, id) in sent {
+        let response = nodes[node].receive()?;
+        if response.id != id {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected response to request {}, got {}", id, response.id),
+            ));
+        }
+
+        match response.body {
+            ResponseBody::Values(values) => {
+                for (position, entry) in positions[node].iter().zip(values) {
+                    entries[*position] = entry;
+                }
+            }
+            body => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expected values, got {:?}", body),
+                ))
+            }
+        }
+    }
+
+    Ok(entries)
+}
 
 pub(crate) fn main() {
     // TODO make the port a config option
     let listener = TcpListener::bind("localhost:3333").unwrap();
     let kv_port = 1338;
-    let kv_stream = RefCell::new(TcpStream::connect(format!("localhost:{kv_port}")).unwrap());
-    let kv_stream_ref = &kv_stream;
-    let mut reader = BufReader::new(kv_stream_ref.borrow_mut().try_clone().unwrap());
+    let mut client = Client::connect(format!("localhost:{kv_port}"), Protocol::Bincode).unwrap();
+
+    // Scans go to every node and MGETs to the owners of their keys
+    let zk = ZooKeeper::connect("localhost:2181", Duration::from_secs(15), LoggingWatcher).unwrap();
+    let (allocations_binary, _) = zk.get_data("/allocations", false).unwrap();
+    let allocations =
+        bincode::deserialize::<Vec<NamespaceAllocation>>(&allocations_binary).unwrap();
+    // A node that owns several ranges is scanned once
+    let mut owners: Vec<String> = Vec::new();
+    for allocation in &allocations {
+        if !owners.contains(&allocation.node) {
+            owners.push(allocation.node.clone());
+        }
+    }
+    let mut nodes: Vec<Client> = owners
+        .iter()
+        .map(|owner| Client::connect(owner, Protocol::Bincode).unwrap())
+        .collect();
+
+    let get_regex = Regex::new(r"GET /get/(\w+) .+").unwrap();
+    let set_regex = Regex::new(r"GET /set/(\w+)/(\w+) .+").unwrap();
+    let delete_regex = Regex::new(r"GET /del/(\w+) .+").unwrap();
+    let compare_and_set_regex = Regex::new(r"GET /cas/(\w+)/(\w+)/(\w+) .+").unwrap();
+    let set_if_absent_regex = Regex::new(r"GET /setnx/(\w+)/(\w+) .+").unwrap();
+    let delete_if_equals_regex = Regex::new(r"GET /deleq/(\w+)/(\w+) .+").unwrap();
+    let increment_regex = Regex::new(r"GET /incr/(\w+) .+").unwrap();
+    let increment_by_regex = Regex::new(r"GET /incrby/(\w+)/(-?\d+) .+").unwrap();
+    let multi_get_regex = Regex::new(r"GET /mget/(\w+(?:,\w+)*) .+").unwrap();
+    let scan_regex = Regex::new(r"GET /scan/(\w+)/(\w*)/(\d+) .+").unwrap();
+    let prefix_scan_regex = Regex::new(r"GET /prefix/(\w+)/(\d+) .+").unwrap();
+    let info_regex = Regex::new(r"GET /info .+").unwrap();
 
     for stream in listener.incoming() {
         let mut stream = stream.unwrap();
@@ -21,64 +131,147 @@ pub(crate) fn main() {
         let buf_reader = BufReader::new(&mut stream);
         let request_line_string = buf_reader.lines().next().unwrap().unwrap();
         let request_line = request_line_string.as_str();
-        let get_regex = Regex::new(r"GET /get/(\w+) .+").unwrap();
-        let set_regex = Regex::new(r"GET /set/(\w+)/(\w+) .+").unwrap();
-        let delete_regex = Regex::new(r"GET /del/(\w+) .+").unwrap();
-
-        let contents: String = if let Some(capture) = set_regex.captures(request_line) {
-            let key = capture[1].to_string();
-            let value = capture[2].to_string();
-
-            kv_stream_ref
-                .borrow_mut()
-                .write_all(
-                    format!(
-                        "{}\n",
-                        serde_json::to_string(&Command::Set { key, value }).unwrap()
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
-            "SET".to_string()
-        } else if let Some(capture) = get_regex.captures(request_line) {
-            let key = capture[1].to_string();
-            kv_stream_ref
-                .borrow_mut()
-                .write_all(
-                    format!(
-                        "{}\n",
-                        serde_json::to_string(&Command::Get { key }).unwrap()
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
 
-            let mut value = String::new();
-            reader.read_line(&mut value).unwrap();
+        // An empty end scans to the last key
+        let scanned = if let Some(capture) = scan_regex.captures(request_line) {
+            let start = capture[1].as_bytes().to_vec();
+            let end = (!capture[2].is_empty()).then(|| capture[2].as_bytes().to_vec());
 
-            value
+            capture[3].parse().ok().map(|limit| {
+                client::scan_nodes(nodes.iter_mut(), limit, |limit, cursor| Command::Scan {
+                    start: start.clone(),
+                    end: end.clone(),
+                    limit,
+                    cursor,
+                })
+                .unwrap()
+            })
+        } else if let Some(capture) = prefix_scan_regex.captures(request_line) {
+            let prefix = capture[1].as_bytes().to_vec();
+
+            capture[2].parse().ok().map(|limit| {
+                client::scan_nodes(nodes.iter_mut(), limit, |limit, cursor| {
+                    Command::PrefixScan {
+                        prefix: prefix.clone(),
+                        limit,
+                        cursor,
+                    }
+                })
+                .unwrap()
+            })
+        } else {
+            None
+        };
+
+        let multi_got = if scanned.is_some() {
+            None
+        } else if let Some(capture) = multi_get_regex.captures(request_line) {
+            let keys = capture[1]
+                .split(',')
+                .map(|key| key.as_bytes().to_vec())
+                .collect();
+
+            Some(multi_get(&mut nodes, &owners, &allocations, keys).unwrap())
+        } else {
+            None
+        };
+
+        let command = if scanned.is_some() || multi_got.is_some() {
+            None
+        } else if let Some(capture) = set_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
+            let value = capture[2].as_bytes().to_vec();
+
+            Some(Command::Set { key, value })
+        } else if let Some(capture) = get_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
+
+            Some(Command::Get { key })
         } else if let Some(capture) = delete_regex.captures(request_line) {
-            let key = capture[1].to_string();
-            kv_stream_ref
-                .borrow_mut()
-                .write_all(
-                    format!(
-                        "{}\n",
-                        serde_json::to_string(&Command::Delete { key }).unwrap()
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
+            let key = capture[1].as_bytes().to_vec();
+
+            Some(Command::Delete { key })
+        } else if let Some(capture) = compare_and_set_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
+            let expected = capture[2].as_bytes().to_vec();
+            let new = capture[3].as_bytes().to_vec();
+
+            Some(Command::CompareAndSet { key, expected, new })
+        } else if let Some(capture) = set_if_absent_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
+            let value = capture[2].as_bytes().to_vec();
+
+            Some(Command::SetIfAbsent { key, value })
+        } else if let Some(capture) = delete_if_equals_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
+            let expected = capture[2].as_bytes().to_vec();
+
+            Some(Command::DeleteIfEquals { key, expected })
+        } else if let Some(capture) = increment_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
+
+            Some(Command::Increment { key, delta: 1 })
+        } else if let Some(capture) = increment_by_regex.captures(request_line) {
+            let key = capture[1].as_bytes().to_vec();
 
-            "DEL".to_string()
+            capture[2]
+                .parse()
+                .ok()
+                .map(|delta| Command::Increment { key, delta })
+        } else if info_regex.is_match(request_line) {
+            Some(Command::Info)
         } else {
-            "UNKNOWN".to_string()
+            None
+        };
+
+        // Values are sent as they are stored, they might not be text
+        let contents = if let Some(entries) = scanned {
+            entries_contents(entries)
+        } else {
+            let body = match multi_got {
+                Some(entries) => Some(ResponseBody::Values(entries)),
+                None => command.map(|command| client.request(command).unwrap().body),
+            };
+
+            match body {
+                Some(ResponseBody::Value { value, .. }) => value,
+                Some(ResponseBody::NotFound) => b"NOT FOUND".to_vec(),
+                Some(ResponseBody::Ok { .. }) => b"OK".to_vec(),
+                Some(ResponseBody::ConditionFailed) => b"CONDITION FAILED".to_vec(),
+                Some(ResponseBody::Counter { value, .. }) => value.to_string().into_bytes(),
+                Some(ResponseBody::Ttl { remaining_ms }) => {
+                    format!("{:?}", remaining_ms).into_bytes()
+                }
+                // One line per key
+                Some(ResponseBody::Values(entries)) => entries
+                    .into_iter()
+                    .map(|entry| entry.map_or(b"NOT FOUND".to_vec(), |entry| entry.value))
+                    .collect::<Vec<_>>()
+                    .join(&b'\n'),
+                Some(ResponseBody::Entries { entries, .. }) => entries_contents(entries),
+                Some(ResponseBody::Info(info)) => format!(
+                    "keys={}\nused_bytes={}\nmax_bytes={}\nevicted_keys={}",
+                    info.keys,
+                    info.used_bytes,
+                    info.max_bytes
+                        .map_or("none".to_string(), |max_bytes| max_bytes.to_string()),
+                    info.evicted_keys
+                )
+                .into_bytes(),
+                Some(ResponseBody::ReplicatedSequence(sequence)) => {
+                    format!("{:?}", sequence).into_bytes()
+                }
+                Some(ResponseBody::Error(error)) => {
+                    format!("ERROR {:?}: {}", error.code, error.message).into_bytes()
+                }
+                None => b"UNKNOWN".to_vec(),
+            }
         };
 
         let contents_length = contents.len();
-        let response =
-            format!("HTTP/1.1 200 OK\r\nContent-Length: {contents_length}\r\n\r\n{contents}");
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {contents_length}\r\n\r\n");
 
         stream.write_all(response.as_bytes()).unwrap();
+        stream.write_all(&contents).unwrap();
     }
 }
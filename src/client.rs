@@ -0,0 +1,242 @@
+use crate::protocol::{self, MessageReader, MessageWriter, Protocol};
+use crate::{Command, Entry, Message, Request, Response, ResponseBody};
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/*
+ * Connection to a KV node. Requests can be pipelined: `send` doesn't wait for the response and
+ * `receive` returns the responses in the order the node sends them, which is matched to the
+ * requests by their id.
+ */
+pub struct Client {
+    reader: MessageReader<TcpStream>,
+    writer: MessageWriter<TcpStream>,
+    next_id: u64,
+}
+
+impl Client {
+    pub fn connect(address: impl ToSocketAddrs, protocol: Protocol) -> IOResult<Client> {
+        Client::open(TcpStream::connect(address)?, protocol)
+    }
+
+    // Connecting, and every read and write after it, fail once they take longer than `timeout`
+    pub fn connect_timeout(
+        address: impl ToSocketAddrs,
+        protocol: Protocol,
+        timeout: Duration,
+    ) -> IOResult<Client> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no address to connect to"))?;
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        Client::open(stream, protocol)
+    }
+
+    fn open(stream: TcpStream, protocol: Protocol) -> IOResult<Client> {
+        let (reader, writer) = protocol::open(stream, protocol)?;
+
+        Ok(Client {
+            reader,
+            writer,
+            next_id: 1,
+        })
+    }
+
+    // Sends the command and returns the id of its request
+    pub fn send(&mut self, command: Command) -> IOResult<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.writer
+            .write_message(&Message::Command(Request { id, command }))?;
+
+        Ok(id)
+    }
+
+    pub fn receive(&mut self) -> IOResult<Response> {
+        loop {
+            match self.reader.next_message()? {
+                None => return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+                Some(Ok(Message::Response(response))) => return Ok(response),
+                // Errors that can't be matched to a request
+                Some(Ok(Message::Error(error))) => println!("Error from the node {:?}", error),
+                Some(Ok(message)) => println!("Unexpected message {:?}", message),
+                Some(Err(error)) => return Err(Error::new(ErrorKind::InvalidData, error.message)),
+            }
+        }
+    }
+
+    pub fn request(&mut self, command: Command) -> IOResult<Response> {
+        let id = self.send(command)?;
+        let response = self.receive()?;
+
+        if response.id != id {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected response to request {}, got {}", id, response.id),
+            ));
+        }
+
+        Ok(response)
+    }
+}
+
+/*
+ * Runs a scan on each of the nodes, following the cursor of each until it runs out or the node
+ * returned `limit` entries, and returns the first `limit` entries in key order. `page` builds the
+ * command for the given limit and cursor. A node can own ranges that are not next to each other,
+ * so the entries of every node are merged by key.
+ */
+pub fn scan_nodes<'a>(
+    nodes: impl IntoIterator<Item = &'a mut Client>,
+    limit: usize,
+    page: impl Fn(usize, Option<Vec<u8>>) -> Command,
+) -> IOResult<Vec<(Vec<u8>, Entry)>> {
+    let mut entries = Vec::new();
+
+    for node in nodes {
+        let mut scanned = 0;
+        let mut cursor = None;
+
+        while scanned < limit {
+            match node.request(page(limit - scanned, cursor))?.body {
+                ResponseBody::Entries {
+                    entries: page,
+                    cursor: next,
+                } => {
+                    scanned += page.len();
+                    entries.extend(page);
+                    cursor = next;
+                }
+                body => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("expected scanned entries, got {:?}", body),
+                    ))
+                }
+            }
+
+            if cursor.is_none() {
+                break;
+            }
+        }
+    }
+
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    entries.truncate(limit);
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scan_nodes, Client};
+    use crate::protocol::{accept, Protocol};
+    use crate::{Command, Entry, Message, Response, ResponseBody};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn entry(value: &str) -> Entry {
+        Entry {
+            value: value.as_bytes().to_vec(),
+            version: 1,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_pipelined_responses_match_request_ids() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut reader, mut writer) = accept(stream).unwrap();
+
+            for _ in 0..2 {
+                let Some(Ok(Message::Command(request))) = reader.next_message().unwrap() else {
+                    panic!("Expected a command");
+                };
+
+                writer
+                    .write_message(&Message::Response(Response {
+                        id: request.id,
+                        body: ResponseBody::NotFound,
+                    }))
+                    .unwrap();
+            }
+        });
+
+        let mut client = Client::connect(address, Protocol::Bincode).unwrap();
+        let first = client.send(Command::Get { key: b"a".to_vec() }).unwrap();
+        let second = client.send(Command::Get { key: b"b".to_vec() }).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(client.receive().unwrap().id, first);
+        assert_eq!(client.receive().unwrap().id, second);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_scanned_entries_of_every_node_are_merged_by_key() {
+        // The first node owns the ranges around the one of the second node
+        let nodes = [vec!["a", "b", "y", "z"], vec!["m", "n"]].map(|keys| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let server = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let (mut reader, mut writer) = accept(stream).unwrap();
+
+                while let Some(Ok(Message::Command(request))) = reader.next_message().unwrap() {
+                    let Command::Scan { limit, .. } = request.command else {
+                        panic!("Expected a scan");
+                    };
+                    let entries = keys
+                        .iter()
+                        .take(limit)
+                        .map(|key| (key.as_bytes().to_vec(), entry(key)))
+                        .collect();
+
+                    writer
+                        .write_message(&Message::Response(Response {
+                            id: request.id,
+                            body: ResponseBody::Entries {
+                                entries,
+                                cursor: None,
+                            },
+                        }))
+                        .unwrap();
+                }
+            });
+
+            (Client::connect(address, Protocol::Json).unwrap(), server)
+        });
+        let (mut clients, servers): (Vec<_>, Vec<_>) = nodes.into_iter().unzip();
+
+        let entries = scan_nodes(clients.iter_mut(), 4, |limit, cursor| Command::Scan {
+            start: b"a".to_vec(),
+            end: None,
+            limit,
+            cursor,
+        })
+        .unwrap();
+        let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+
+        assert_eq!(
+            keys,
+            vec![b"a".to_vec(), b"b".to_vec(), b"m".to_vec(), b"n".to_vec()]
+        );
+
+        drop(clients);
+        for server in servers {
+            server.join().unwrap();
+        }
+    }
+}
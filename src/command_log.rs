@@ -0,0 +1,606 @@
+use crate::segment::{LogReader, Segment};
+use crate::snapshot::{self, Snapshot};
+use crate::Command;
+use std::fs::{self, File};
+use std::io::{BufReader, Result as IOResult};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/*
+ * When are the writes to the log flushed to stable storage.
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum Durability {
+    // `fsync` after every write, before the write is acknowledged
+    Always,
+    // Writes that happen within the same window share a single `fsync`. Each write waits for the
+    // `fsync` that covers it before being acknowledged.
+    GroupCommit { window: Duration },
+    // `fsync` periodically from a background thread. Writes are acknowledged before they are
+    // durable, a crash can lose up to `every` worth of writes.
+    Interval { every: Duration },
+    // Never `fsync`, let the OS decide when to flush its buffers
+    Os,
+}
+
+struct SyncState {
+    synced: usize, // sequence of the last record known to be on stable storage
+    syncing: bool, // a thread is currently running the `fsync` for a group
+}
+
+/*
+ * Tracks which records are durable and runs the `fsync`s according to the `Durability` of the
+ * log. It's kept apart from the `CommandLog` so that threads can wait for their writes to be
+ * durable without holding the lock that protects the log, otherwise there would be nothing to
+ * group.
+ */
+pub struct LogSync {
+    file: Mutex<File>,
+    durability: Durability,
+    written: AtomicUsize, // sequence of the last record written to the file
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+impl LogSync {
+    fn new(file: File, durability: Durability, sequence: usize) -> Arc<LogSync> {
+        let log_sync = Arc::new(LogSync {
+            file: Mutex::new(file),
+            durability,
+            written: AtomicUsize::new(sequence),
+            state: Mutex::new(SyncState {
+                synced: sequence,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        });
+
+        if let Durability::Interval { every } = durability {
+            let log_sync = Arc::downgrade(&log_sync);
+            thread::spawn(move || LogSync::sync_periodically(log_sync, every));
+        }
+
+        log_sync
+    }
+
+    fn sync_periodically(log_sync: Weak<LogSync>, every: Duration) {
+        loop {
+            thread::sleep(every);
+
+            // The log is gone, nothing left to sync
+            let Some(log_sync) = log_sync.upgrade() else {
+                return;
+            };
+
+            log_sync.sync_data().unwrap();
+        }
+    }
+
+    fn sync_data(&self) -> IOResult<()> {
+        self.file.lock().unwrap().sync_data()
+    }
+
+    // The log moved to a new file, further `fsync`s have to go there
+    fn replace_file(&self, file: File) {
+        *self.file.lock().unwrap() = file;
+    }
+
+    fn written(&self, sequence: usize) {
+        if let Durability::Always = self.durability {
+            self.sync_data().unwrap();
+        }
+
+        self.written.store(sequence, Ordering::Release);
+    }
+
+    // Blocks until the record with the given sequence is durable, as defined by the
+    // `Durability` of the log.
+    pub fn wait(&self, sequence: usize) {
+        let Durability::GroupCommit { window } = self.durability else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        while state.synced < sequence {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            // This thread leads the group. Give other writers the window to join it and then
+            // `fsync` everything that was written up to that point.
+            state.syncing = true;
+            drop(state);
+
+            thread::sleep(window);
+            let target = self.written.load(Ordering::Acquire);
+            let result = self.sync_data();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result.unwrap();
+        }
+    }
+}
+
+/*
+ * Commands of the log from a sequence up to the last one written when the range was taken. The
+ * segments are opened right away, so the range can be read without holding the log: the segments
+ * compacted in the meantime are still read and the commands written after it are left out.
+ */
+pub struct LogRange {
+    readers: Vec<LogReader<BufReader<File>>>,
+    from: usize,
+    to: usize,
+}
+
+impl LogRange {
+    // Sequence of the last command of the range
+    pub fn last_sequence(&self) -> usize {
+        self.to
+    }
+
+    // The records after the range are never read, they might be half written
+    pub fn read(self, mut apply: impl FnMut(usize, Command) -> IOResult<()>) -> IOResult<()> {
+        if self.from > self.to {
+            return Ok(());
+        }
+
+        for mut reader in self.readers {
+            while let Some((sequence, command)) = reader.next_record()? {
+                if sequence >= self.from {
+                    apply(sequence, command)?;
+                }
+
+                if sequence >= self.to {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Log of the commands applied to a KV. The log lives in its own directory and it's split into
+ * segments that roll over once they reach `segment_bytes`, so that old records can be dropped (or
+ * copied) a whole segment at a time.
+ */
+pub struct CommandLog {
+    directory: String,      // directory with the segments of the log
+    segment: Segment,       // segment the records are appended to
+    segment_bytes: u64,     // size after which the segment rolls over
+    sequence: usize,        // sequence of the last record written to the log
+    truncated_bytes: u64,   // bytes dropped from the tail of the log when it was opened
+    log_sync: Arc<LogSync>, // makes the written records durable
+}
+
+impl CommandLog {
+    pub fn new(directory: String, durability: Durability, segment_bytes: u64) -> CommandLog {
+        let path = Path::new(&directory);
+        fs::create_dir_all(path).unwrap();
+
+        let snapshot_sequence = snapshot::latest_sequence(&directory).unwrap();
+        let segments = Segment::list(path).unwrap();
+        let (segment, truncated_bytes) = match segments.split_last() {
+            Some((last, sealed)) => {
+                for first_sequence in sealed {
+                    Segment::verify_index(path, *first_sequence).unwrap();
+                }
+
+                Segment::recover(path, *last).unwrap()
+            }
+            None => (Segment::create(path, snapshot_sequence + 1).unwrap(), 0),
+        };
+
+        // The log might be empty after a compaction, the records up to that point live in the
+        // snapshot
+        let sequence = segment
+            .last_sequence()
+            .unwrap_or(segment.first_sequence() - 1)
+            .max(snapshot_sequence);
+
+        println!("Sequence {}", sequence);
+
+        let log_sync = LogSync::new(segment.file().try_clone().unwrap(), durability, sequence);
+
+        CommandLog {
+            directory,
+            segment,
+            segment_bytes,
+            sequence,
+            truncated_bytes,
+            log_sync,
+        }
+    }
+
+    // Calls `apply` with every command in the log with a sequence greater or equal than
+    // `sequence`, in the order they were appended
+    pub fn read_from(&self, sequence: usize, mut apply: impl FnMut(usize, Command)) {
+        self.range_from(sequence)
+            .unwrap()
+            .read(|sequence, command| {
+                apply(sequence, command);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    // The commands from `sequence` to the last one written so far
+    pub fn range_from(&self, sequence: usize) -> IOResult<LogRange> {
+        let path = Path::new(&self.directory);
+        let segments = Segment::list(path)?;
+        // The first segment that can hold `sequence` is the last one that starts at or before it
+        let start = segments
+            .partition_point(|first_sequence| *first_sequence <= sequence)
+            .saturating_sub(1);
+        let readers = segments[start..]
+            .iter()
+            .map(|first_sequence| Segment::reader_from(path, *first_sequence, sequence))
+            .collect::<IOResult<_>>()?;
+
+        Ok(LogRange {
+            readers,
+            from: sequence,
+            to: self.sequence,
+        })
+    }
+
+    // Calls `apply` with every command in the log with a sequence greater than `after`, in the
+    // order they were appended
+    pub fn replay(&self, after: usize, apply: impl FnMut(usize, Command)) {
+        self.read_from(after + 1, apply);
+    }
+
+    // Sequence of the oldest record that can still be read from the log
+    pub fn first_sequence(&self) -> usize {
+        Segment::list(Path::new(&self.directory)).unwrap()[0]
+    }
+
+    pub fn latest_snapshot(&self) -> Option<Snapshot> {
+        snapshot::load_latest(&self.directory).unwrap()
+    }
+
+    /*
+     * Drops the segments whose records are all up to `sequence` (included). They must be covered
+     * by a snapshot or flushed by the storage engine already. The segment that is being appended
+     * to is never dropped.
+     */
+    pub fn compact(&mut self, sequence: usize) {
+        let path = Path::new(&self.directory);
+        let segments = Segment::list(path).unwrap();
+
+        for pair in segments.windows(2) {
+            if pair[1] <= sequence + 1 && pair[0] != self.segment.first_sequence() {
+                println!("Removing segment {}", pair[0]);
+                Segment::remove(path, pair[0]).unwrap();
+            }
+        }
+
+        sync_directory(path).unwrap();
+    }
+
+    /*
+     * Drops every record in the log, which continues from `sequence`. Used when the state the log
+     * describes is replaced by a snapshot taken at `sequence`.
+     */
+    pub fn reset(&mut self, sequence: usize) {
+        let path = Path::new(&self.directory);
+
+        for first_sequence in Segment::list(path).unwrap() {
+            Segment::remove(path, first_sequence).unwrap();
+        }
+
+        self.segment = Segment::create(path, sequence + 1).unwrap();
+        sync_directory(path).unwrap();
+        self.log_sync
+            .replace_file(self.segment.file().try_clone().unwrap());
+        self.sequence = sequence;
+    }
+
+    /*
+     * Seals the current segment and starts a new one. The records of the sealed segment are made
+     * durable first because any `fsync` from now on goes to the new segment, and there might be
+     * writers still waiting for them.
+     */
+    fn roll(&mut self, first_sequence: usize) {
+        let path = Path::new(&self.directory);
+
+        if !matches!(self.log_sync.durability, Durability::Os) {
+            self.segment.sync().unwrap();
+        }
+
+        self.segment = Segment::create(path, first_sequence).unwrap();
+        sync_directory(path).unwrap();
+        self.log_sync
+            .replace_file(self.segment.file().try_clone().unwrap());
+    }
+
+    fn write(&mut self, command: &Command, sequence: usize) {
+        if !command.is_logged() {
+            panic!("Can't log this command");
+        }
+
+        if self.segment.length() >= self.segment_bytes && self.segment.last_sequence().is_some() {
+            self.roll(sequence);
+        }
+
+        self.segment.append(sequence, command).unwrap();
+        self.log_sync.written(sequence);
+    }
+
+    pub fn append(&mut self, command: &Command) -> usize {
+        let sequence = self.sequence + 1;
+
+        self.write(command, sequence);
+        self.sequence = sequence;
+        self.sequence
+    }
+
+    pub fn replicated_append(&mut self, command: &Command, sequence: usize) -> usize {
+        self.write(command, sequence);
+        self.sequence = sequence;
+        self.sequence
+    }
+
+    pub fn sequence(&self) -> usize {
+        self.sequence
+    }
+
+    // Handle to wait for appended records to be durable once the log itself is no longer borrowed
+    pub fn log_sync(&self) -> Arc<LogSync> {
+        self.log_sync.clone()
+    }
+
+    pub fn truncated_bytes(&self) -> u64 {
+        self.truncated_bytes
+    }
+
+    pub fn directory(&self) -> &String {
+        &self.directory
+    }
+}
+
+fn sync_directory(directory: &Path) -> IOResult<()> {
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandLog, Durability};
+    use crate::segment::{encode_record, LogReader};
+    use crate::snapshot::{self, Snapshot};
+    use crate::{Command, Entry};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Write;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+    fn log_directory(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rustkv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::Set {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    fn entry(value: &[u8], version: usize) -> Entry {
+        Entry {
+            value: value.to_vec(),
+            version,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_replay_preserves_keys_and_values() {
+        let directory = log_directory("replay");
+        let commands = vec![
+            set("key with spaces", "a=b#c\nd"),
+            set("ünïcode", ""),
+            Command::Set {
+                key: vec![0xff, 0x00, b'\n'],
+                value: vec![0x00, 0x9f, 0x92, 0x96],
+            },
+            Command::Delete {
+                key: b"key with spaces".to_vec(),
+            },
+        ];
+
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        for command in &commands {
+            log.append(command);
+        }
+        drop(log);
+
+        let log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        let mut replayed = Vec::new();
+        log.replay(0, |sequence, command| replayed.push((sequence, command)));
+
+        assert_eq!(log.sequence(), 4);
+        assert_eq!(
+            replayed,
+            commands
+                .into_iter()
+                .enumerate()
+                .map(|(index, command)| (index + 1, command))
+                .collect::<Vec<_>>()
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_read_from_any_sequence_across_segments() {
+        let directory = log_directory("segments");
+        // Small enough for every record to roll the segment over
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, 1);
+        for index in 1..=10 {
+            log.append(&set(&index.to_string(), "value"));
+        }
+
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 20);
+
+        for start in 1..=11 {
+            let mut sequences = Vec::new();
+            log.read_from(start, |sequence, _| sequences.push(sequence));
+            assert_eq!(sequences, (start..=10).collect::<Vec<_>>());
+        }
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_drops_segments_covered_by_the_snapshot() {
+        let directory = log_directory("compaction");
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, 1);
+        for index in 0..3 {
+            log.append(&set(&index.to_string(), &index.to_string()));
+        }
+
+        let snapshot = Snapshot {
+            sequence: 2,
+            entries: BTreeMap::from([
+                (b"0".to_vec(), entry(b"0", 1)),
+                (b"1".to_vec(), entry(b"1", 2)),
+            ]),
+        };
+        snapshot::write(&directory, &snapshot).unwrap();
+        log.compact(2);
+        drop(log);
+
+        let log = CommandLog::new(directory.clone(), Durability::Os, 1);
+        let mut sequences = Vec::new();
+        log.replay(0, |sequence, _| sequences.push(sequence));
+
+        assert_eq!(log.sequence(), 3);
+        assert_eq!(log.first_sequence(), 3);
+        assert_eq!(sequences, vec![3]);
+        assert_eq!(log.latest_snapshot(), Some(snapshot));
+
+        fs::remove_dir_all(&directory).unwrap();
+        fs::remove_file(format!("{directory}.snapshot.2")).unwrap();
+    }
+
+    #[test]
+    fn test_group_commit_waits_for_concurrent_writers() {
+        let directory = log_directory("group-commit");
+        let log = Arc::new(RwLock::new(CommandLog::new(
+            directory.clone(),
+            Durability::GroupCommit {
+                window: Duration::from_millis(20),
+            },
+            SEGMENT_BYTES,
+        )));
+
+        let handles = (0..8)
+            .map(|index| {
+                let log = log.clone();
+                thread::spawn(move || {
+                    let (sequence, log_sync) = {
+                        let mut log = log.write().unwrap();
+                        let sequence = log.append(&set(&index.to_string(), "value"));
+                        (sequence, log.log_sync())
+                    };
+
+                    log_sync.wait(sequence);
+                    assert!(log_sync.state.lock().unwrap().synced >= sequence);
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(log.read().unwrap().sequence(), 8);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_detected() {
+        let directory = log_directory("checksum");
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        log.append(&set("key", "value"));
+        drop(log);
+
+        let segment = format!("{directory}/{:020}.log", 1);
+        let mut bytes = fs::read(&segment).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        let mut reader = LogReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.next_record().is_err());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write_is_truncated_on_recovery() {
+        let directory = log_directory("torn");
+        let segment = format!("{directory}/{:020}.log", 1);
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        log.append(&set("a", "1"));
+        log.append(&set("b", "2"));
+        drop(log);
+
+        let valid_length = fs::metadata(&segment).unwrap().len();
+        let torn = encode_record(3, &set("c", "3"));
+        let mut file = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&torn[..torn.len() - 2]).unwrap();
+        drop(file);
+
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        assert_eq!(log.truncated_bytes(), torn.len() as u64 - 2);
+        assert_eq!(log.sequence(), 2);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), valid_length);
+
+        assert_eq!(log.append(&Command::Delete { key: b"a".to_vec() }), 3);
+        let mut sequences = Vec::new();
+        log.replay(0, |sequence, _| sequences.push(sequence));
+        assert_eq!(sequences, vec![1, 2, 3]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_record_with_a_corrupt_length_is_truncated_on_recovery() {
+        let directory = log_directory("corrupt-length");
+        let segment = format!("{directory}/{:020}.log", 1);
+        let mut log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        log.append(&set("a", "1"));
+        drop(log);
+
+        let valid_length = fs::metadata(&segment).unwrap().len();
+        let mut file = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        file.write_all(&[0; 12]).unwrap();
+        drop(file);
+
+        let log = CommandLog::new(directory.clone(), Durability::Os, SEGMENT_BYTES);
+        assert_eq!(log.truncated_bytes(), 16);
+        assert_eq!(log.sequence(), 1);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), valid_length);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use clap::ValueEnum;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum EvictionPolicy {
+    // Writes that don't fit are rejected
+    NoEviction,
+    // The least recently used keys go first
+    AllKeysLru,
+    // The least frequently used keys go first, the least recently used among them
+    AllKeysLfu,
+    // The keys closest to expiring go first. Keys without a TTL are never evicted.
+    VolatileTtl,
+}
+
+struct KeyStats {
+    bytes: u64,
+    last_used: u64,
+    uses: u64,
+    expires_at: Option<u64>,
+}
+
+/*
+ * Bytes taken by the keys and values of the KV, and the order in which the policy evicts them.
+ * Reads and writes of a key count as uses of it. Keys are only tracked when there's a limit.
+ */
+pub struct Eviction {
+    policy: EvictionPolicy,
+    max_bytes: Option<u64>,
+    used_bytes: u64,
+    // Ticks on every use, it orders the uses for LRU
+    clock: u64,
+    keys: HashMap<Vec<u8>, KeyStats>,
+    // Keys that can be evicted, the first to go first
+    order: BTreeSet<((u64, u64), Vec<u8>)>,
+    evicted_keys: u64,
+}
+
+impl Eviction {
+    pub fn new(policy: EvictionPolicy, max_bytes: Option<u64>) -> Eviction {
+        Eviction {
+            policy,
+            max_bytes,
+            used_bytes: 0,
+            clock: 0,
+            keys: HashMap::new(),
+            order: BTreeSet::new(),
+            evicted_keys: 0,
+        }
+    }
+
+    // Keeps the accounting, with new limits
+    pub fn limit(&mut self, policy: EvictionPolicy, max_bytes: Option<u64>) {
+        let keys: Vec<Vec<u8>> = self.keys.keys().cloned().collect();
+
+        self.policy = policy;
+        self.max_bytes = max_bytes;
+        self.order.clear();
+
+        if max_bytes.is_none() {
+            self.clear();
+            return;
+        }
+
+        for key in keys {
+            if let Some(rank) = self.rank(&self.keys[&key]) {
+                self.order.insert((rank, key));
+            }
+        }
+    }
+
+    fn rank(&self, stats: &KeyStats) -> Option<(u64, u64)> {
+        match self.policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => Some((stats.last_used, 0)),
+            EvictionPolicy::AllKeysLfu => Some((stats.uses, stats.last_used)),
+            EvictionPolicy::VolatileTtl => stats.expires_at.map(|expires_at| (expires_at, 0)),
+        }
+    }
+
+    fn update(&mut self, key: &[u8], change: impl FnOnce(&mut KeyStats)) {
+        let Some(mut stats) = self.keys.remove(key) else {
+            return;
+        };
+
+        if let Some(rank) = self.rank(&stats) {
+            self.order.remove(&(rank, key.to_vec()));
+        }
+
+        self.clock += 1;
+        stats.last_used = self.clock;
+        stats.uses += 1;
+        change(&mut stats);
+
+        if let Some(rank) = self.rank(&stats) {
+            self.order.insert((rank, key.to_vec()));
+        }
+        self.keys.insert(key.to_vec(), stats);
+    }
+
+    pub fn written(&mut self, key: &[u8], bytes: u64, expires_at: Option<u64>) {
+        if self.max_bytes.is_none() {
+            return;
+        }
+
+        if !self.keys.contains_key(key) {
+            self.keys.insert(
+                key.to_vec(),
+                KeyStats {
+                    bytes: 0,
+                    last_used: 0,
+                    uses: 0,
+                    expires_at: None,
+                },
+            );
+        }
+
+        let previous = self.keys[key].bytes;
+        self.used_bytes = self.used_bytes + bytes - previous;
+        self.update(key, |stats| {
+            stats.bytes = bytes;
+            stats.expires_at = expires_at;
+        });
+    }
+
+    pub fn used(&mut self, key: &[u8]) {
+        self.update(key, |_| ());
+    }
+
+    pub fn removed(&mut self, key: &[u8]) {
+        if let Some(stats) = self.keys.remove(key) {
+            if let Some(rank) = self.rank(&stats) {
+                self.order.remove(&(rank, key.to_vec()));
+            }
+
+            self.used_bytes -= stats.bytes;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.order.clear();
+        self.used_bytes = 0;
+    }
+
+    // Bytes the key takes, 0 if it's not in the KV
+    pub fn bytes(&self, key: &[u8]) -> u64 {
+        self.keys.get(key).map_or(0, |stats| stats.bytes)
+    }
+
+    /*
+     * Keys to evict for `incoming` more bytes to fit in the limit, leaving the ones in `keep`
+     * alone. `None` if the policy can't make them fit.
+     */
+    pub fn victims(&self, incoming: u64, keep: &[&[u8]]) -> Option<Vec<Vec<u8>>> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Some(Vec::new());
+        };
+
+        let mut needed = (self.used_bytes + incoming).saturating_sub(max_bytes);
+        let mut victims = Vec::new();
+
+        for (_, key) in &self.order {
+            if needed == 0 {
+                break;
+            }
+
+            if keep.contains(&key.as_slice()) {
+                continue;
+            }
+
+            needed = needed.saturating_sub(self.keys[key].bytes);
+            victims.push(key.clone());
+        }
+
+        (needed == 0).then_some(victims)
+    }
+
+    pub fn evicted(&mut self, keys: usize) {
+        self.evicted_keys += keys as u64;
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    pub fn keys(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Eviction, EvictionPolicy};
+
+    #[test]
+    fn test_victims_follow_the_policy() {
+        let write = |eviction: &mut Eviction| {
+            eviction.written(b"a", 10, Some(300));
+            eviction.written(b"b", 10, None);
+            eviction.written(b"c", 10, Some(200));
+            eviction.used(b"a");
+            eviction.used(b"a");
+            eviction.used(b"b");
+        };
+
+        let mut eviction = Eviction::new(EvictionPolicy::AllKeysLru, Some(30));
+        write(&mut eviction);
+        assert_eq!(eviction.used_bytes(), 30);
+        assert_eq!(eviction.victims(0, &[]), Some(vec![]));
+        assert_eq!(eviction.victims(5, &[]), Some(vec![b"c".to_vec()]));
+        assert_eq!(
+            eviction.victims(15, &[b"c"]),
+            Some(vec![b"a".to_vec(), b"b".to_vec()])
+        );
+        assert_eq!(eviction.victims(31, &[]), None);
+
+        eviction.limit(EvictionPolicy::AllKeysLfu, Some(30));
+        assert_eq!(
+            eviction.victims(20, &[]),
+            Some(vec![b"c".to_vec(), b"b".to_vec()])
+        );
+
+        eviction.limit(EvictionPolicy::VolatileTtl, Some(30));
+        assert_eq!(
+            eviction.victims(20, &[]),
+            Some(vec![b"c".to_vec(), b"a".to_vec()])
+        );
+        assert_eq!(eviction.victims(21, &[]), None);
+
+        eviction.limit(EvictionPolicy::NoEviction, Some(30));
+        assert_eq!(eviction.victims(1, &[]), None);
+
+        eviction.written(b"a", 4, None);
+        eviction.removed(b"b");
+        assert_eq!(eviction.used_bytes(), 14);
+        assert_eq!(eviction.keys(), 2);
+    }
+}
@@ -1,29 +1,535 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, ops::RangeInclusive};
+use snapshot::Snapshot;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub mod client;
+pub mod command_log;
+pub mod eviction;
+pub mod lsm;
+pub mod protocol;
+pub mod segment;
+pub mod snapshot;
+pub mod sstable;
+pub mod storage;
+
+// Keys and values are arbitrary bytes. `serde_bytes` keeps them compact in bincode, which would
+// otherwise encode them as a sequence of integers.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum Command {
-    Set { key: String, value: String },
-    Delete { key: String },
-    Get { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Delete {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    // Sets the value of the key, which expires `ttl_ms` later
+    SetWithTtl {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Expire {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        ttl_ms: u64,
+    },
+    // Removes the expiration of the key
+    Persist {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Ttl {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    // What `SetWithTtl` and `Expire` are logged and replicated as. The deadline is absolute, in
+    // milliseconds since the UNIX epoch, so that replaying the command later doesn't extend the
+    // life of the key.
+    SetExpiring {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    ExpireAt {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        expires_at: u64,
+    },
+    // Conditional writes. They are evaluated against the current value of the key and, when the
+    // condition holds, logged and replicated as the `Set` or `Delete` they turn into.
+    CompareAndSet {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        expected: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        new: Vec<u8>,
+    },
+    SetIfAbsent {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    DeleteIfEquals {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        expected: Vec<u8>,
+    },
+    // Writes that only apply if the key is still at the version the client read
+    SetIfVersion {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        if_version: usize,
+    },
+    DeleteIfVersion {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        if_version: usize,
+    },
+    // Adds `delta` to the value of the key, a 64-bit signed integer in decimal. Keys that don't
+    // exist count as 0. Logged and replicated as the `Set` of the resulting value.
+    Increment {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        delta: i64,
+    },
+    // Batches. Writes are logged and replicated as a single record, so either every key in the
+    // batch is written or none is.
+    MultiGet {
+        #[serde(with = "bytes_list")]
+        keys: Vec<Vec<u8>>,
+    },
+    MultiSet {
+        #[serde(with = "bytes_pairs")]
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    MultiDelete {
+        #[serde(with = "bytes_list")]
+        keys: Vec<Vec<u8>>,
+    },
+    // Live keys from `start` (inclusive) to `end` (exclusive, unbounded if `None`) in key order, at
+    // most `limit` of them. The next page starts after the `cursor` the previous one returned.
+    Scan {
+        #[serde(with = "serde_bytes")]
+        start: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        end: Option<Vec<u8>>,
+        limit: usize,
+        #[serde(with = "serde_bytes")]
+        cursor: Option<Vec<u8>>,
+    },
+    // Same as `Scan`, for the keys that start with `prefix`
+    PrefixScan {
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
+        limit: usize,
+        #[serde(with = "serde_bytes")]
+        cursor: Option<Vec<u8>>,
+    },
+    // Statistics of the node
+    Info,
+    // How much of the log of `owner` the node has replicated, asked by the coordinator to pick the
+    // replica that takes over the range of an owner that is gone
+    ReplicatedSequence {
+        owner: String,
+    },
+    // Entries of the range of an owner that is gone, written by the node that takes it over with
+    // their expiration. They get the sequence of the command as their version, like any other
+    // write. Only logged and replicated, clients can't send it.
+    TakeOver {
+        #[serde(with = "bytes_keyed")]
+        entries: Vec<(Vec<u8>, Entry)>,
+    },
+}
+
+// `serde_bytes` for each of the keys of a batch
+mod bytes_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(keys: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(keys.iter().map(|key| Bytes::new(key)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        Ok(Vec::<ByteBuf>::deserialize(deserializer)?
+            .into_iter()
+            .map(ByteBuf::into_vec)
+            .collect())
+    }
+}
+
+// `serde_bytes` for each of the keys and values of a batch
+mod bytes_pairs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub fn serialize<S: Serializer>(
+        entries: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            entries
+                .iter()
+                .map(|(key, value)| (Bytes::new(key), Bytes::new(value))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pairs, D::Error> {
+        Ok(Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value.into_vec()))
+            .collect())
+    }
+}
+
+// `serde_bytes` for the keys of the entries of a scan or a takeover
+mod bytes_keyed {
+    use crate::Entry;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(
+        entries: &[(Vec<u8>, Entry)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(entries.iter().map(|(key, entry)| (Bytes::new(key), entry)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Vec<u8>, Entry)>, D::Error> {
+        Ok(Vec::<(ByteBuf, Entry)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, entry)| (key.into_vec(), entry))
+            .collect())
+    }
+}
+
+/*
+ * Value of a key in the KV. The version is the sequence of the last command that wrote the key,
+ * so it grows every time the key changes and it's the same in the node and its replicas.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Entry {
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+    pub version: usize,
+    // Milliseconds since the UNIX epoch
+    pub expires_at: Option<u64>,
+}
+
+impl Command {
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Set { key, .. }
+            | Command::Delete { key }
+            | Command::Get { key }
+            | Command::SetWithTtl { key, .. }
+            | Command::Expire { key, .. }
+            | Command::Persist { key }
+            | Command::Ttl { key }
+            | Command::SetExpiring { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::CompareAndSet { key, .. }
+            | Command::SetIfAbsent { key, .. }
+            | Command::DeleteIfEquals { key, .. }
+            | Command::SetIfVersion { key, .. }
+            | Command::DeleteIfVersion { key, .. }
+            | Command::Increment { key, .. } => vec![key],
+            Command::MultiGet { keys } | Command::MultiDelete { keys } => {
+                keys.iter().map(|key| key.as_slice()).collect()
+            }
+            Command::MultiSet { entries } => {
+                entries.iter().map(|(key, _)| key.as_slice()).collect()
+            }
+            Command::TakeOver { entries } => {
+                entries.iter().map(|(key, _)| key.as_slice()).collect()
+            }
+            // Scans read whichever keys of the range the node owns
+            Command::Scan { .. }
+            | Command::PrefixScan { .. }
+            | Command::Info
+            | Command::ReplicatedSequence { .. } => vec![],
+        }
+    }
+
+    // Commands that don't change the KV
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Command::Get { .. }
+                | Command::Ttl { .. }
+                | Command::MultiGet { .. }
+                | Command::Scan { .. }
+                | Command::PrefixScan { .. }
+                | Command::Info
+                | Command::ReplicatedSequence { .. }
+        )
+    }
+
+    // Commands that can be written to the command log and replicated as they are
+    pub fn is_logged(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Delete { .. }
+                | Command::Persist { .. }
+                | Command::SetExpiring { .. }
+                | Command::ExpireAt { .. }
+                | Command::MultiSet { .. }
+                | Command::MultiDelete { .. }
+                | Command::TakeOver { .. }
+        )
+    }
+
+    // Turns the TTL of the command into a deadline counted from `now`
+    pub fn with_deadline(self, now: u64) -> Command {
+        match self {
+            Command::SetWithTtl { key, value, ttl_ms } => Command::SetExpiring {
+                key,
+                value,
+                expires_at: now.saturating_add(ttl_ms),
+            },
+            Command::Expire { key, ttl_ms } => Command::ExpireAt {
+                key,
+                expires_at: now.saturating_add(ttl_ms),
+            },
+            command => command,
+        }
+    }
+}
+
+// Keys and values are shown as text, even if they are not
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = String::from_utf8_lossy;
+
+        match self {
+            Command::Set { key, value } => write!(f, "SET {} = {}", text(key), text(value)),
+            Command::Delete { key } => write!(f, "DEL {}", text(key)),
+            Command::Get { key } => write!(f, "GET {}", text(key)),
+            Command::SetWithTtl { key, value, ttl_ms } => {
+                write!(f, "SET {} = {} TTL {}ms", text(key), text(value), ttl_ms)
+            }
+            Command::Expire { key, ttl_ms } => write!(f, "EXPIRE {} {}ms", text(key), ttl_ms),
+            Command::Persist { key } => write!(f, "PERSIST {}", text(key)),
+            Command::Ttl { key } => write!(f, "TTL {}", text(key)),
+            Command::SetExpiring {
+                key,
+                value,
+                expires_at,
+            } => write!(
+                f,
+                "SET {} = {} EXPIRES AT {}",
+                text(key),
+                text(value),
+                expires_at
+            ),
+            Command::ExpireAt { key, expires_at } => {
+                write!(f, "EXPIREAT {} {}", text(key), expires_at)
+            }
+            Command::CompareAndSet { key, expected, new } => {
+                write!(f, "CAS {} {} => {}", text(key), text(expected), text(new))
+            }
+            Command::SetIfAbsent { key, value } => {
+                write!(f, "SETNX {} = {}", text(key), text(value))
+            }
+            Command::DeleteIfEquals { key, expected } => {
+                write!(f, "DELEQ {} {}", text(key), text(expected))
+            }
+            Command::SetIfVersion {
+                key,
+                value,
+                if_version,
+            } => write!(
+                f,
+                "SET {} = {} IF VERSION {}",
+                text(key),
+                text(value),
+                if_version
+            ),
+            Command::DeleteIfVersion { key, if_version } => {
+                write!(f, "DEL {} IF VERSION {}", text(key), if_version)
+            }
+            Command::Increment { key, delta } => write!(f, "INCRBY {} {}", text(key), delta),
+            // Batches can be large, only their size is shown
+            Command::MultiGet { keys } => write!(f, "MGET ({} keys)", keys.len()),
+            Command::MultiSet { entries } => write!(f, "MSET ({} keys)", entries.len()),
+            Command::MultiDelete { keys } => write!(f, "MDEL ({} keys)", keys.len()),
+            Command::Scan {
+                start, end, limit, ..
+            } => match end {
+                Some(end) => write!(f, "SCAN {}..{} LIMIT {}", text(start), text(end), limit),
+                None => write!(f, "SCAN {}.. LIMIT {}", text(start), limit),
+            },
+            Command::PrefixScan { prefix, limit, .. } => {
+                write!(f, "SCAN {}* LIMIT {}", text(prefix), limit)
+            }
+            Command::Info => write!(f, "INFO"),
+            Command::ReplicatedSequence { owner } => write!(f, "REPLICATED {}", owner),
+            Command::TakeOver { entries } => write!(f, "TAKEOVER ({} keys)", entries.len()),
+        }
+    }
+}
+
+// Milliseconds since the UNIX epoch, the unit of the expiration deadlines
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// A command sent by a client. The `id` is chosen by the client and echoed in the `Response`, so
+// a client can have several requests in flight on the same connection.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Request {
+    pub id: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Response {
+    pub id: u64,
+    pub body: ResponseBody,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ResponseBody {
+    Value {
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        version: usize,
+    },
+    NotFound,
+    // The write was applied by the node with this sequence, which is also the new version of the
+    // key
+    Ok {
+        sequence: usize,
+    },
+    // Time left until the key expires, `None` if it doesn't
+    Ttl {
+        remaining_ms: Option<u64>,
+    },
+    // The condition of a conditional write doesn't hold, nothing was written
+    ConditionFailed,
+    // New value of the counter after an `Increment`
+    Counter {
+        value: i64,
+        sequence: usize,
+    },
+    // Entries of the keys of a `MultiGet`, in the order they were asked for
+    Values(Vec<Option<Entry>>),
+    // A page of a scan. `cursor` is set when there might be more keys after it.
+    Entries {
+        #[serde(with = "bytes_keyed")]
+        entries: Vec<(Vec<u8>, Entry)>,
+        #[serde(with = "serde_bytes")]
+        cursor: Option<Vec<u8>>,
+    },
+    Info(Info),
+    // Sequence of the last command of the owner the node applied, `None` if it doesn't replicate it
+    ReplicatedSequence(Option<usize>),
+    Error(Error),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Info {
+    pub keys: usize,
+    // Bytes taken by the keys and values
+    pub used_bytes: u64,
+    pub max_bytes: Option<u64>,
+    // Keys evicted to stay under `max_bytes` since the node started
+    pub evicted_keys: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Connect {
     pub from: String,
+    // Sequence of the last command the replica applied from the node it connects to
+    pub sequence: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ConnectOk {
-    pub map: HashMap<String, String>,
+    // Only sent when the replica can't catch up from the log, because the commands it's missing
+    // have been compacted away. Otherwise the missing commands follow the `ConnectOk` as
+    // `ReplicationCommand`s.
+    pub snapshot: Option<Snapshot>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
-    Command(Command),
+    Command(Request),
+    Response(Response),
     ReplicationCommand(ReplicationCommand),
     Connect(Connect),
     ConnectOk(ConnectOk),
+    ReplicationAck(ReplicationAck),
+    Error(Error),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    // The message is not valid JSON
+    Malformed,
+    // The message is valid JSON but not a message or command we know about
+    UnknownCommand,
+    // The message is not expected on this connection (e.g. a GET replicated to a replica)
+    UnexpectedMessage,
+    // The key belongs to the range of another node
+    WrongOwner,
+    // The node doesn't accept writes while its replicas are unavailable
+    ReadOnly,
+    // Not enough replicas acknowledged the write in time. The write was applied by the node, so
+    // it might still reach them.
+    NotReplicated,
+    // The value of the key is not an integer, so it can't be incremented
+    NotAnInteger,
+    // The increment doesn't fit in a 64-bit signed integer
+    Overflow,
+    // The write doesn't fit in the memory limit of the node and its policy can't evict enough
+    OutOfMemory,
+}
+
+// Reply to a message that couldn't be handled. The connection stays open after it. Errors about
+// a `Request` are sent in its `Response` instead.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Error {
+        Error {
+            code,
+            message: message.into(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,14 +538,35 @@ pub struct ReplicationCommand {
     pub sequence: usize,
 }
 
+// Sent by a replica once it has applied every command up to `sequence`
 #[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicationAck {
+    pub sequence: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct NamespaceAllocation {
     pub node: String,
     pub range: RangeInclusive<char>,
+    // Nodes that keep a copy of the range, written by `node` as it applies the commands
+    pub replicas: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl NamespaceAllocation {
+    // Keys are allocated to the nodes by their first byte
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key.first()
+            .is_some_and(|byte| self.range.contains(&(*byte as char)))
+    }
+}
+
+// Registration of a node with the coordinator, the data of its `/nodes/node-{node_id}` ZK node
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Node {
     pub node_id: u8,
     pub address: String,
+    // Range the node proposes to own
+    pub range: RangeInclusive<char>,
+    // Owners whose ranges the node has replicated before, so it keeps replicating them
+    pub replicating: Vec<String>,
 }
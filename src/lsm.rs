@@ -0,0 +1,798 @@
+use crate::snapshot::Snapshot;
+use crate::sstable::{table_filename, SSTable, TableWriter};
+use crate::storage::{is_empty_range, StorageEngine};
+use crate::Entry;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result as IOResult, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// The manifest lists the tables of every level. It's rewritten as a whole every time they change:
+//
+//   | magic | crc32 of the payload (u32 LE) | payload |
+//
+// where the payload is the bincode encoding of a `Manifest`. Table files that are not in the
+// manifest are left over from a flush or a compaction that didn't finish, and are removed.
+const MANIFEST: &str = "MANIFEST";
+// Locked while the tables are open, so that a second `Levels` on the directory waits for the first
+// one to be dropped instead of removing the tables it's writing
+const LOCK: &str = "LOCK";
+const MAGIC: &[u8; 4] = b"RKVM";
+const LEVELS: usize = 7;
+// Each level after the first one holds this many times more bytes than the one above
+const LEVEL_MULTIPLIER: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    // Size of the memtable after which it's flushed to a table in level 0
+    pub memtable_bytes: usize,
+    // Size after which a compaction starts a new table
+    pub table_bytes: u64,
+    // Tables in level 0 that trigger a compaction into level 1
+    pub level0_tables: usize,
+    pub level1_bytes: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_bytes: 4 * 1024 * 1024,
+            table_bytes: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    // Sequence of the last command whose effects are in the tables
+    durable_sequence: usize,
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+#[derive(Debug, Clone)]
+struct Table {
+    id: u64,
+    sstable: Arc<SSTable>,
+}
+
+struct State {
+    // Level 0 has the tables flushed from the memtable, newest first, and their keys overlap. The
+    // tables of every other level are sorted by key and don't overlap.
+    levels: Vec<Vec<Table>>,
+    next_id: u64,
+    durable_sequence: Option<usize>,
+    // Bumped when the tables are replaced by a snapshot, so that a compaction that started before
+    // doesn't bring the old tables back
+    generation: u64,
+    // Last key of the latest compaction of each level, the next one starts after it
+    compaction_pointers: Vec<Vec<u8>>,
+}
+
+type Records<'a> = Box<dyn Iterator<Item = (Vec<u8>, Option<Entry>)> + 'a>;
+
+// Merges sources sorted by key. When several have the same key, the first of them wins and the
+// others are skipped.
+struct Merge<'a> {
+    sources: Vec<Peekable<Records<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Records<'a>>) -> Merge<'a> {
+        Merge {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = (Vec<u8>, Option<Entry>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .sources
+            .iter_mut()
+            .filter_map(|source| source.peek().map(|(key, _)| key.clone()))
+            .min()?;
+        let mut record = None;
+
+        for source in &mut self.sources {
+            if source.peek().is_some_and(|(next, _)| *next == key) {
+                let next = source.next();
+                record = record.or(next);
+            }
+        }
+
+        record
+    }
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn sync_directory(directory: &Path) -> IOResult<()> {
+    File::open(directory)?.sync_all()
+}
+
+fn owned_bound(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    bound.map(|key| key.to_vec())
+}
+
+/*
+ * Tables of an `LsmEngine`, shared with the thread that compacts them. Readers only hold the lock
+ * to pick the tables they need.
+ */
+pub struct Levels {
+    directory: PathBuf,
+    options: LsmOptions,
+    state: Mutex<State>,
+    _lock: File,
+}
+
+impl Levels {
+    fn open(directory: &Path, options: LsmOptions) -> IOResult<Levels> {
+        fs::create_dir_all(directory)?;
+
+        let lock = File::create(directory.join(LOCK))?;
+        lock.lock()?;
+
+        let manifest = Levels::read_manifest(directory)?;
+        let mut levels = vec![Vec::new(); LEVELS];
+        let mut listed = HashSet::new();
+
+        if let Some(manifest) = &manifest {
+            for (level, ids) in manifest.levels.iter().enumerate() {
+                for id in ids {
+                    let sstable = SSTable::open(&table_filename(directory, *id))?;
+
+                    listed.insert(*id);
+                    levels[level].push(Table {
+                        id: *id,
+                        sstable: Arc::new(sstable),
+                    });
+                }
+            }
+        }
+
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".sst"))
+                .and_then(|id| id.parse::<u64>().ok());
+
+            if id.is_some_and(|id| !listed.contains(&id)) {
+                println!("Removing unlisted table {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(Levels {
+            directory: directory.to_path_buf(),
+            options,
+            state: Mutex::new(State {
+                levels,
+                next_id: manifest.as_ref().map_or(1, |manifest| manifest.next_id),
+                durable_sequence: manifest.map(|manifest| manifest.durable_sequence),
+                generation: 0,
+                compaction_pointers: vec![Vec::new(); LEVELS],
+            }),
+            _lock: lock,
+        })
+    }
+
+    fn read_manifest(directory: &Path) -> IOResult<Option<Manifest>> {
+        let mut bytes = Vec::new();
+
+        match File::open(directory.join(MANIFEST)) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not a manifest file"));
+        }
+
+        let checksum = u32::from_le_bytes(bytes[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+        let payload = &bytes[MAGIC.len() + 4..];
+
+        if crc32fast::hash(payload) != checksum {
+            return Err(invalid_data("manifest checksum mismatch"));
+        }
+
+        bincode::deserialize(payload)
+            .map(Some)
+            .map_err(|_| invalid_data("malformed manifest"))
+    }
+
+    // Replaces the manifest with the one of `state`, through a temporary file like snapshots
+    fn write_manifest(&self, state: &State) -> IOResult<()> {
+        let manifest = Manifest {
+            durable_sequence: state.durable_sequence.unwrap_or(0),
+            next_id: state.next_id,
+            levels: state
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let payload = bincode::serialize(&manifest).unwrap();
+        let path = self.directory.join(MANIFEST);
+        let tmp_path = self.directory.join(format!("{MANIFEST}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+
+        file.write_all(MAGIC)?;
+        file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, &path)?;
+        sync_directory(&self.directory)
+    }
+
+    fn next_id(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        state.next_id - 1
+    }
+
+    fn durable_sequence(&self) -> Option<usize> {
+        self.state.lock().unwrap().durable_sequence
+    }
+
+    // Tables that might have the key, the newest first
+    fn tables_for(&self, key: &[u8]) -> Vec<Arc<SSTable>> {
+        let state = self.state.lock().unwrap();
+        let mut tables: Vec<Arc<SSTable>> = state.levels[0]
+            .iter()
+            .filter(|table| table.sstable.overlaps(key, key))
+            .map(|table| table.sstable.clone())
+            .collect();
+
+        for level in &state.levels[1..] {
+            let i = level.partition_point(|table| table.sstable.last_key() < key);
+
+            if let Some(table) = level
+                .get(i)
+                .filter(|table| table.sstable.first_key() <= key)
+            {
+                tables.push(table.sstable.clone());
+            }
+        }
+
+        tables
+    }
+
+    // Records of every table from `from` onwards, one source per table of level 0 and one per
+    // level after it, the newest first
+    fn sources<'a>(&self, from: Bound<&[u8]>) -> Vec<Records<'a>> {
+        let state = self.state.lock().unwrap();
+        let mut sources: Vec<Records<'a>> = Vec::new();
+
+        for table in &state.levels[0] {
+            sources.push(Box::new(table.sstable.iter(from)));
+        }
+
+        for level in &state.levels[1..] {
+            let from = owned_bound(from);
+            let tables: Vec<Arc<SSTable>> =
+                level.iter().map(|table| table.sstable.clone()).collect();
+
+            sources.push(Box::new(tables.into_iter().flat_map(move |table| {
+                table.iter(from.as_ref().map(|key| key.as_slice()))
+            })));
+        }
+
+        sources
+    }
+
+    // Adds the table flushed from the memtable, which has the effects of the commands up to
+    // `sequence`
+    fn flushed(&self, table: Option<Table>, sequence: usize) -> IOResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(table) = table {
+            state.levels[0].insert(0, table);
+        }
+        state.durable_sequence = Some(sequence);
+
+        self.write_manifest(&state)
+    }
+
+    /*
+     * Drops every table, used before restoring a snapshot. The manifest goes first: until the
+     * snapshot is flushed, a restart has to restore it again.
+     */
+    fn clear(&self) -> IOResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let tables: Vec<Table> = state.levels.iter_mut().flat_map(std::mem::take).collect();
+
+        state.generation += 1;
+        state.durable_sequence = None;
+
+        match fs::remove_file(self.directory.join(MANIFEST)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => sync_directory(&self.directory)?,
+        }
+
+        for table in tables {
+            fs::remove_file(table.sstable.path())?;
+        }
+
+        Ok(())
+    }
+
+    fn max_bytes(&self, level: usize) -> u64 {
+        self.options.level1_bytes * LEVEL_MULTIPLIER.pow(level as u32 - 1)
+    }
+
+    // Level that needs to be compacted into the next one the most, if any does
+    fn pick(&self, state: &State) -> Option<usize> {
+        if state.levels[0].len() >= self.options.level0_tables {
+            return Some(0);
+        }
+
+        (1..LEVELS - 1).find(|level| {
+            let bytes: u64 = state.levels[*level]
+                .iter()
+                .map(|table| table.sstable.bytes())
+                .sum();
+
+            bytes > self.max_bytes(*level)
+        })
+    }
+
+    /*
+     * Merges the tables of a level that grew too large with the ones they overlap in the next
+     * level. Every table of level 0 is compacted at once, otherwise a single table is, going round
+     * the keys of the level. Returns whether there was anything to compact.
+     */
+    pub fn compact(&self) -> IOResult<bool> {
+        let (level, inputs, overlapping, generation, bottom) = {
+            let mut state = self.state.lock().unwrap();
+            let Some(level) = self.pick(&state) else {
+                return Ok(false);
+            };
+
+            let inputs = if level == 0 {
+                state.levels[0].clone()
+            } else {
+                let pointer = &state.compaction_pointers[level];
+                let tables = &state.levels[level];
+                let i = tables
+                    .iter()
+                    .position(|table| table.sstable.first_key() > pointer.as_slice())
+                    .unwrap_or(0);
+
+                vec![tables[i].clone()]
+            };
+
+            let first = inputs
+                .iter()
+                .map(|table| table.sstable.first_key())
+                .min()
+                .unwrap()
+                .to_vec();
+            let last = inputs
+                .iter()
+                .map(|table| table.sstable.last_key())
+                .max()
+                .unwrap()
+                .to_vec();
+            let overlapping: Vec<Table> = state.levels[level + 1]
+                .iter()
+                .filter(|table| table.sstable.overlaps(&first, &last))
+                .cloned()
+                .collect();
+            // Tombstones only have to be kept while there might be older entries below them
+            let bottom = state.levels[level + 2..]
+                .iter()
+                .all(|tables| tables.is_empty());
+
+            state.compaction_pointers[level] = last;
+            (level, inputs, overlapping, state.generation, bottom)
+        };
+
+        println!(
+            "Compacting {} tables of level {} with {} of level {}",
+            inputs.len(),
+            level,
+            overlapping.len(),
+            level + 1
+        );
+
+        let mut sources: Vec<Records> = inputs
+            .iter()
+            .map(|table| Box::new(table.sstable.iter(Bound::Unbounded)) as Records)
+            .collect();
+        let lower: Vec<Arc<SSTable>> = overlapping
+            .iter()
+            .map(|table| table.sstable.clone())
+            .collect();
+        sources.push(Box::new(
+            lower
+                .into_iter()
+                .flat_map(|table| table.iter(Bound::Unbounded)),
+        ));
+
+        let mut outputs = Vec::new();
+        let mut writer: Option<(u64, TableWriter)> = None;
+
+        for (key, entry) in Merge::new(sources) {
+            if entry.is_none() && bottom {
+                continue;
+            }
+
+            if writer.is_none() {
+                let id = self.next_id();
+                writer = Some((id, TableWriter::create(&self.directory, id)?));
+            }
+
+            let (_, table_writer) = writer.as_mut().unwrap();
+            table_writer.add(key, entry)?;
+
+            if table_writer.bytes() >= self.options.table_bytes {
+                let (id, table_writer) = writer.take().unwrap();
+                outputs.push(Table {
+                    id,
+                    sstable: Arc::new(table_writer.finish()?),
+                });
+            }
+        }
+
+        if let Some((id, table_writer)) = writer {
+            outputs.push(Table {
+                id,
+                sstable: Arc::new(table_writer.finish()?),
+            });
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.generation != generation {
+            for table in outputs {
+                fs::remove_file(table.sstable.path())?;
+            }
+
+            return Ok(false);
+        }
+
+        let compacted: HashSet<u64> = inputs
+            .iter()
+            .chain(&overlapping)
+            .map(|table| table.id)
+            .collect();
+
+        state.levels[level].retain(|table| !compacted.contains(&table.id));
+        state.levels[level + 1].retain(|table| !compacted.contains(&table.id));
+        state.levels[level + 1].extend(outputs);
+        state.levels[level + 1].sort_by(|a, b| a.sstable.first_key().cmp(b.sstable.first_key()));
+        self.write_manifest(&state)?;
+        drop(state);
+
+        // Readers might still have them open, which is fine once they are unlinked
+        for table in inputs.iter().chain(&overlapping) {
+            fs::remove_file(table.sstable.path())?;
+        }
+        sync_directory(&self.directory)?;
+
+        Ok(true)
+    }
+}
+
+/*
+ * Log-structured merge-tree. Writes go to the memtable, which is flushed to an immutable table in
+ * level 0 once it's large enough, and the tables are compacted down the levels in the background.
+ * The command log is the write-ahead log: after a restart the memtable is rebuilt by replaying the
+ * commands after the `durable_sequence`.
+ */
+pub struct LsmEngine {
+    levels: Arc<Levels>,
+    // `None` is a tombstone, which hides the key in the tables
+    memtable: BTreeMap<Vec<u8>, Option<Entry>>,
+    memtable_bytes: usize,
+    // Sequence of the last command applied
+    sequence: usize,
+    // Deadlines of the keys that expire. They might be stale, so they are checked against the
+    // entry of the key before reporting it as expired.
+    expirations: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl LsmEngine {
+    pub fn open(directory: impl AsRef<Path>, options: LsmOptions) -> IOResult<LsmEngine> {
+        let levels = Levels::open(directory.as_ref(), options)?;
+        let mut engine = LsmEngine {
+            sequence: levels.durable_sequence().unwrap_or(0),
+            levels: Arc::new(levels),
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            expirations: BTreeSet::new(),
+        };
+
+        engine.expirations = engine
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .filter_map(|(key, entry)| entry.expires_at.map(|expires_at| (expires_at, key)))
+            .collect();
+
+        Ok(engine)
+    }
+
+    // Handle to compact the tables from another thread
+    pub fn levels(&self) -> Arc<Levels> {
+        self.levels.clone()
+    }
+
+    fn write(&mut self, key: Vec<u8>, entry: Option<Entry>) {
+        self.memtable_bytes += key.len() + entry.as_ref().map_or(0, |entry| entry.value.len()) + 32;
+        self.memtable.insert(key, entry);
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &[u8]) -> Option<Entry> {
+        if let Some(entry) = self.memtable.get(key) {
+            return entry.clone();
+        }
+
+        for table in self.levels.tables_for(key) {
+            // Tables are immutable, one that can't be read is corrupt
+            if let Some(entry) = table.get(key).unwrap() {
+                return entry;
+            }
+        }
+
+        None
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        if let Some(expires_at) = entry.expires_at {
+            self.expirations.insert((expires_at, key.clone()));
+        }
+
+        self.write(key, Some(entry));
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.write(key.to_vec(), None);
+    }
+
+    fn scan<'a>(
+        &'a self,
+        from: Bound<&[u8]>,
+        to: Bound<&[u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Entry)> + 'a> {
+        if is_empty_range(from, to) {
+            return Box::new(std::iter::empty());
+        }
+
+        let mut sources: Vec<Records<'a>> = vec![Box::new(
+            self.memtable
+                .range::<[u8], _>((from, to))
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+        )];
+        sources.extend(self.levels.sources(from));
+
+        let to = owned_bound(to);
+
+        Box::new(
+            Merge::new(sources)
+                .take_while(move |(key, _)| match &to {
+                    Bound::Included(to) => key <= to,
+                    Bound::Excluded(to) => key < to,
+                    Bound::Unbounded => true,
+                })
+                .filter_map(|(key, entry)| entry.map(|entry| (key, entry))),
+        )
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.levels.clear().unwrap();
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        self.expirations.clear();
+
+        for (key, entry) in snapshot.entries {
+            self.put(key, entry);
+        }
+
+        self.sequence = snapshot.sequence;
+        self.flush();
+    }
+
+    fn expired(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let mut expired = Vec::new();
+
+        while self
+            .expirations
+            .first()
+            .is_some_and(|(expires_at, _)| *expires_at <= now)
+        {
+            let (expires_at, key) = self.expirations.pop_first().unwrap();
+
+            if self
+                .get(&key)
+                .is_some_and(|entry| entry.expires_at == Some(expires_at))
+            {
+                expired.push(key);
+            }
+        }
+
+        expired
+    }
+
+    fn applied(&mut self, sequence: usize) {
+        self.sequence = sequence;
+
+        if self.memtable_bytes >= self.levels.options.memtable_bytes {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.memtable.is_empty() && self.levels.durable_sequence() == Some(self.sequence) {
+            return;
+        }
+
+        let table = if self.memtable.is_empty() {
+            None
+        } else {
+            let id = self.levels.next_id();
+            let mut writer = TableWriter::create(&self.levels.directory, id).unwrap();
+
+            for (key, entry) in std::mem::take(&mut self.memtable) {
+                writer.add(key, entry).unwrap();
+            }
+
+            Some(Table {
+                id,
+                sstable: Arc::new(writer.finish().unwrap()),
+            })
+        };
+
+        println!("Flushed the memtable at sequence {}", self.sequence);
+        self.levels.flushed(table, self.sequence).unwrap();
+        self.memtable_bytes = 0;
+    }
+
+    fn durable_sequence(&self) -> Option<usize> {
+        self.levels.durable_sequence()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LsmEngine, LsmOptions};
+    use crate::storage::StorageEngine;
+    use crate::Entry;
+    use std::fs;
+    use std::ops::Bound;
+    use std::thread;
+    use std::time::Duration;
+
+    fn directory(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rustkv-lsm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn entry(version: usize) -> Entry {
+        Entry {
+            value: version.to_string().into_bytes(),
+            version,
+            expires_at: None,
+        }
+    }
+
+    fn options() -> LsmOptions {
+        LsmOptions {
+            memtable_bytes: 512,
+            table_bytes: 1024,
+            level0_tables: 2,
+            level1_bytes: 4096,
+        }
+    }
+
+    #[test]
+    fn test_flushed_tables_survive_a_restart() {
+        let directory = directory("restart");
+        let mut engine = LsmEngine::open(&directory, options()).unwrap();
+        assert_eq!(engine.durable_sequence(), None);
+
+        engine.put(b"a".to_vec(), entry(1));
+        engine.applied(1);
+        engine.put(b"b".to_vec(), entry(2));
+        engine.applied(2);
+        engine.flush();
+        // Only in the memtable, the log has it
+        engine.put(b"c".to_vec(), entry(3));
+        engine.applied(3);
+        drop(engine);
+
+        let engine = LsmEngine::open(&directory, options()).unwrap();
+        assert_eq!(engine.durable_sequence(), Some(2));
+        assert_eq!(engine.get(b"a"), Some(entry(1)));
+        assert_eq!(engine.get(b"b"), Some(entry(2)));
+        assert_eq!(engine.get(b"c"), None);
+    }
+
+    #[test]
+    fn test_compaction_keeps_the_newest_entries() {
+        let directory = directory("compaction");
+        let mut engine = LsmEngine::open(&directory, options()).unwrap();
+        let mut sequence = 0;
+
+        for round in 0..20 {
+            for i in 0..20 {
+                sequence += 1;
+                let key = format!("key-{:02}", i).into_bytes();
+
+                if round == 19 && i % 2 == 0 {
+                    engine.delete(&key);
+                } else {
+                    engine.put(key, entry(sequence));
+                }
+                engine.applied(sequence);
+            }
+        }
+        engine.flush();
+
+        let levels = engine.levels();
+        while levels.compact().unwrap() {}
+
+        let state = levels.state.lock().unwrap();
+        assert!(state.levels[0].len() < options().level0_tables);
+        assert!(state.levels[1..].iter().any(|tables| !tables.is_empty()));
+        drop(state);
+        drop(levels);
+        drop(engine);
+
+        let engine = LsmEngine::open(&directory, options()).unwrap();
+        for i in 0..20 {
+            let key = format!("key-{:02}", i).into_bytes();
+
+            if i % 2 == 0 {
+                assert_eq!(engine.get(&key), None);
+            } else {
+                assert_eq!(engine.get(&key), Some(entry(380 + i + 1)));
+            }
+        }
+
+        let keys: Vec<Vec<u8>> = engine
+            .scan(Bound::Included(b"key-05"), Bound::Excluded(b"key-10"))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![b"key-05".to_vec(), b"key-07".to_vec(), b"key-09".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_the_directory_is_opened_once_the_previous_engine_is_closed() {
+        let directory = directory("lock");
+        let mut engine = LsmEngine::open(&directory, options()).unwrap();
+        engine.put(b"a".to_vec(), entry(1));
+        engine.applied(1);
+        engine.flush();
+
+        let reopened = {
+            let directory = directory.clone();
+            thread::spawn(move || LsmEngine::open(&directory, options()).unwrap())
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!reopened.is_finished());
+
+        drop(engine);
+        let engine = reopened.join().unwrap();
+        assert_eq!(engine.get(b"a"), Some(entry(1)));
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{Error, ErrorCode, Message};
+use clap::ValueEnum;
+use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Read, Result as IOResult, Write};
+use std::net::TcpStream;
+
+// A connection speaks JSON lines unless it starts with the handshake:
+//
+//   | HANDSHAKE | version (u8) |
+//
+// which the node answers with its own version. After that, every message is sent as
+//
+//   | length of the payload (u32 LE) | payload |
+//
+// where the payload is the bincode encoding of a `Message`. A JSON line can't start with a zero
+// byte, so both kind of clients can connect to the same port.
+const HANDSHAKE: u8 = 0;
+const VERSION: u8 = 1;
+// Guards against allocating whatever a corrupt length says
+const MAX_FRAME_BYTES: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Protocol {
+    // Newline delimited JSON. Handy to debug with netcat.
+    Json,
+    // Length prefixed bincode
+    Bincode,
+}
+
+fn invalid_data(message: impl Into<String>) -> IOError {
+    IOError::new(ErrorKind::InvalidData, message.into())
+}
+
+pub fn encode(protocol: Protocol, message: &Message) -> Vec<u8> {
+    match protocol {
+        Protocol::Json => {
+            let mut buffer = serde_json::to_vec(message).unwrap();
+            buffer.push(b'\n');
+            buffer
+        }
+        Protocol::Bincode => {
+            let payload = bincode::serialize(message).unwrap();
+            let mut buffer = Vec::with_capacity(4 + payload.len());
+
+            buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&payload);
+            buffer
+        }
+    }
+}
+
+// Frames that are not a message are reported as an `Error` to the sender
+pub fn decode(protocol: Protocol, frame: &[u8]) -> Result<Message, Error> {
+    match protocol {
+        Protocol::Json => serde_json::from_slice::<Message>(frame).map_err(|e| {
+            if e.is_data() {
+                Error::new(ErrorCode::UnknownCommand, e.to_string())
+            } else {
+                Error::new(ErrorCode::Malformed, e.to_string())
+            }
+        }),
+        Protocol::Bincode => bincode::deserialize::<Message>(frame)
+            .map_err(|e| Error::new(ErrorCode::Malformed, e.to_string())),
+    }
+}
+
+pub struct MessageReader<R> {
+    reader: BufReader<R>,
+    protocol: Protocol,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(reader: R, protocol: Protocol) -> Self {
+        MessageReader {
+            reader: BufReader::new(reader),
+            protocol,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    // Next frame without its framing, `None` once the connection is closed
+    pub fn next_frame(&mut self) -> IOResult<Option<Vec<u8>>> {
+        match self.protocol {
+            Protocol::Json => {
+                let mut line = Vec::new();
+
+                if self.reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+
+                Ok(Some(line))
+            }
+            Protocol::Bincode => {
+                let mut length = [0; 4];
+
+                match self.reader.read_exact(&mut length) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+
+                let length = u32::from_le_bytes(length) as usize;
+                if length > MAX_FRAME_BYTES {
+                    return Err(invalid_data(format!(
+                        "frame of {length} bytes is too large"
+                    )));
+                }
+
+                let mut payload = vec![0; length];
+                self.reader.read_exact(&mut payload)?;
+
+                Ok(Some(payload))
+            }
+        }
+    }
+
+    pub fn next_message(&mut self) -> IOResult<Option<Result<Message, Error>>> {
+        Ok(self
+            .next_frame()?
+            .map(|frame| decode(self.protocol, &frame)))
+    }
+}
+
+pub struct MessageWriter<W> {
+    writer: W,
+    protocol: Protocol,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(writer: W, protocol: Protocol) -> Self {
+        MessageWriter { writer, protocol }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn write_message(&mut self, message: &Message) -> IOResult<()> {
+        self.writer.write_all(&encode(self.protocol, message))
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+/*
+ * Starts a connection opened to a node with the given protocol, handshaking first if it's not
+ * JSON.
+ */
+pub fn open(
+    stream: TcpStream,
+    protocol: Protocol,
+) -> IOResult<(MessageReader<TcpStream>, MessageWriter<TcpStream>)> {
+    let mut writer = stream.try_clone()?;
+
+    if protocol == Protocol::Bincode {
+        let mut reader = stream.try_clone()?;
+        let mut reply = [0; 2];
+
+        writer.write_all(&[HANDSHAKE, VERSION])?;
+        reader.read_exact(&mut reply)?;
+
+        if reply != [HANDSHAKE, VERSION] {
+            return Err(invalid_data(format!(
+                "the node doesn't support protocol version {VERSION}"
+            )));
+        }
+    }
+
+    Ok((
+        MessageReader::new(stream, protocol),
+        MessageWriter::new(writer, protocol),
+    ))
+}
+
+/*
+ * Starts a connection accepted by the node, in the protocol the other end chose.
+ */
+pub fn accept(stream: TcpStream) -> IOResult<(MessageReader<TcpStream>, MessageWriter<TcpStream>)> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let protocol = if reader.fill_buf()?.first() == Some(&HANDSHAKE) {
+        let mut handshake = [0; 2];
+        reader.read_exact(&mut handshake)?;
+        writer.write_all(&[HANDSHAKE, VERSION])?;
+
+        if handshake[1] != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported protocol version {}",
+                handshake[1]
+            )));
+        }
+
+        Protocol::Bincode
+    } else {
+        Protocol::Json
+    };
+
+    Ok((
+        MessageReader { reader, protocol },
+        MessageWriter::new(writer, protocol),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, decode, encode, open, Protocol};
+    use crate::snapshot::Snapshot;
+    use crate::{Command, ConnectOk, Entry, ErrorCode, Message, Request};
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn set(id: u64) -> Request {
+        Request {
+            id,
+            command: Command::Set {
+                key: b"key".to_vec(),
+                value: vec![0x00, 0xff, b'\n', b'"'],
+            },
+        }
+    }
+
+    #[test]
+    fn test_accept_negotiates_the_protocol_of_the_client() {
+        for protocol in [Protocol::Json, Protocol::Bincode] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let client = thread::spawn(move || {
+                let (_, mut writer) = open(TcpStream::connect(address).unwrap(), protocol).unwrap();
+
+                writer.write_message(&Message::Command(set(1))).unwrap();
+                writer.write_message(&Message::Command(set(2))).unwrap();
+            });
+
+            let (stream, _) = listener.accept().unwrap();
+            let (mut reader, _) = accept(stream).unwrap();
+
+            assert_eq!(reader.protocol(), protocol);
+            for id in [1, 2] {
+                match reader.next_message().unwrap().unwrap().unwrap() {
+                    Message::Command(request) => assert_eq!(request, set(id)),
+                    message => panic!("Unexpected message {:?}", message),
+                }
+            }
+            assert!(reader.next_message().unwrap().is_none());
+
+            client.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_malformed_json_line_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"{\"Command\"\n{\"Foo\":1}\n").unwrap();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let (mut reader, _) = accept(stream).unwrap();
+
+        let error = reader.next_message().unwrap().unwrap().unwrap_err();
+        assert_eq!(error.code, ErrorCode::Malformed);
+        let error = reader.next_message().unwrap().unwrap().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnknownCommand);
+
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_binary_snapshot_round_trips_in_both_protocols() {
+        let snapshot = Snapshot {
+            sequence: 7,
+            entries: BTreeMap::from([(
+                vec![0xff, 0x00],
+                Entry {
+                    value: vec![0x00, b'\n', 0xfe],
+                    version: 3,
+                    expires_at: Some(1_700_000_000_000),
+                },
+            )]),
+        };
+
+        for protocol in [Protocol::Json, Protocol::Bincode] {
+            let message = Message::ConnectOk(ConnectOk {
+                snapshot: Some(snapshot.clone()),
+            });
+            let mut frame = encode(protocol, &message);
+            match protocol {
+                Protocol::Json => frame.truncate(frame.len() - 1),
+                Protocol::Bincode => frame = frame.split_off(4),
+            }
+
+            match decode(protocol, &frame).unwrap() {
+                Message::ConnectOk(ConnectOk {
+                    snapshot: Some(decoded),
+                }) => assert_eq!(decoded, snapshot),
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+    }
+}
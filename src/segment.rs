@@ -0,0 +1,453 @@
+use crate::Command;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{
+    BufReader, BufWriter, Error, ErrorKind, Read, Result as IOResult, Seek, SeekFrom, Write,
+};
+use std::path::{Path, PathBuf};
+
+// Every segment starts with this header so that we can tell apart a segment from any other file
+// and, if the record layout ever changes, which version of it was used to write the file.
+const MAGIC: &[u8; 4] = b"RKVL";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+// Each record is framed as:
+//
+//   | payload length (u32 LE) | crc32 of the payload (u32 LE) | payload |
+//
+// where the payload is the bincode encoding of a `Record`.
+const RECORD_HEADER_LEN: usize = 8;
+// Guards against allocating whatever a corrupt length says. A record holds a single command, which
+// can't be larger than the frame it was received in.
+const MAX_RECORD_BYTES: usize = 512 * 1024 * 1024;
+
+// Each index entry is the sequence of a record followed by its offset in the segment, both as
+// u64 LE. Entries are in the same order as the records.
+const INDEX_ENTRY_LEN: u64 = 16;
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    sequence: usize,
+    command: &'a Command,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    sequence: usize,
+    command: Command,
+}
+
+pub fn encode_record(sequence: usize, command: &Command) -> Vec<u8> {
+    let payload = bincode::serialize(&RecordRef { sequence, command }).unwrap();
+    let mut buffer = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buffer.extend_from_slice(&payload);
+    buffer
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_header(reader: &mut impl Read) -> IOResult<()> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("not a command log segment".to_string()));
+    }
+
+    if header[MAGIC.len()] != FORMAT_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported command log format version {}",
+            header[MAGIC.len()]
+        )));
+    }
+
+    Ok(())
+}
+
+/*
+ * Reads the records of a segment in the order they were written.
+ *
+ * A record that was only partially written (e.g. the process crashed in the middle of an append)
+ * is reported as an `UnexpectedEof` error, while a segment that ends right after a complete
+ * record is a clean end of the segment.
+ */
+pub struct LogReader<R: Read> {
+    reader: R,
+    position: u64, // offset right after the last record read successfully
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(mut reader: R) -> IOResult<Self> {
+        read_header(&mut reader)?;
+
+        Ok(LogReader {
+            reader,
+            position: HEADER_LEN as u64,
+        })
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn next_record(&mut self) -> IOResult<Option<(usize, Command)>> {
+        let mut record_header = [0; RECORD_HEADER_LEN];
+        let mut read = 0;
+
+        while read < RECORD_HEADER_LEN {
+            match self.reader.read(&mut record_header[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let length = u32::from_le_bytes(record_header[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(record_header[4..8].try_into().unwrap());
+        if length > MAX_RECORD_BYTES {
+            return Err(invalid_data(format!(
+                "record of {length} bytes is too large"
+            )));
+        }
+
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload)?;
+
+        if crc32fast::hash(&payload) != checksum {
+            return Err(invalid_data("record checksum mismatch".to_string()));
+        }
+
+        let record = bincode::deserialize::<Record>(&payload)
+            .map_err(|e| invalid_data(format!("malformed record: {e}")))?;
+
+        self.position += (RECORD_HEADER_LEN + length) as u64;
+
+        Ok(Some((record.sequence, record.command)))
+    }
+}
+
+impl<R: Read + Seek> LogReader<R> {
+    // Reader that starts at the record at `offset`
+    pub fn at(reader: R, offset: u64) -> IOResult<Self> {
+        let mut log_reader = LogReader::new(reader)?;
+
+        log_reader.reader.seek(SeekFrom::Start(offset))?;
+        log_reader.position = offset;
+
+        Ok(log_reader)
+    }
+}
+
+fn segment_path(directory: &Path, first_sequence: usize) -> PathBuf {
+    directory.join(format!("{first_sequence:020}.log"))
+}
+
+fn index_path(directory: &Path, first_sequence: usize) -> PathBuf {
+    directory.join(format!("{first_sequence:020}.index"))
+}
+
+fn index_entry(sequence: usize, offset: u64) -> [u8; INDEX_ENTRY_LEN as usize] {
+    let mut entry = [0; INDEX_ENTRY_LEN as usize];
+
+    entry[0..8].copy_from_slice(&(sequence as u64).to_le_bytes());
+    entry[8..16].copy_from_slice(&offset.to_le_bytes());
+    entry
+}
+
+fn open_for_append(path: &Path) -> IOResult<File> {
+    File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+}
+
+/*
+ * One of the files the command log is split into. Segments are named after the sequence of the
+ * first record they hold (or will hold, if they are still empty) and are paired with an index
+ * that maps each sequence to the offset of its record, so that readers can start at any
+ * sequence without scanning the segment.
+ *
+ * The index is derived data, it's rebuilt from the segment whenever it can't be trusted.
+ */
+pub struct Segment {
+    first_sequence: usize,
+    last_sequence: Option<usize>, // `None` while the segment is empty
+    file: File,
+    index: File,
+    length: u64,
+}
+
+impl Segment {
+    // Sequences of the first record of each segment in the directory, in ascending order
+    pub fn list(directory: &Path) -> IOResult<Vec<usize>> {
+        let mut segments = Vec::new();
+
+        for entry in fs::read_dir(directory)? {
+            let name = entry?.file_name();
+            let Some(first_sequence) = name.to_str().and_then(|name| name.strip_suffix(".log"))
+            else {
+                continue;
+            };
+
+            if let Ok(first_sequence) = first_sequence.parse::<usize>() {
+                segments.push(first_sequence);
+            }
+        }
+
+        segments.sort();
+        Ok(segments)
+    }
+
+    pub fn create(directory: &Path, first_sequence: usize) -> IOResult<Segment> {
+        let mut file = File::options()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(segment_path(directory, first_sequence))?;
+        let mut header = MAGIC.to_vec();
+        header.push(FORMAT_VERSION);
+        file.write_all(&header)?;
+
+        Ok(Segment {
+            first_sequence,
+            last_sequence: None,
+            file,
+            index: File::create(index_path(directory, first_sequence))?,
+            length: HEADER_LEN as u64,
+        })
+    }
+
+    /*
+     * Opens the segment to keep appending to it. Only the last segment of a log can have been
+     * interrupted in the middle of a write, so this validates every record and drops whatever
+     * follows the last valid one. Returns the segment and the number of bytes dropped.
+     */
+    pub fn recover(directory: &Path, first_sequence: usize) -> IOResult<(Segment, u64)> {
+        let path = segment_path(directory, first_sequence);
+
+        // A file shorter than the header can only be the result of a crash while the segment was
+        // being created, there are no records in it yet so it's safe to create it again.
+        if fs::metadata(&path)?.len() < HEADER_LEN as u64 {
+            fs::remove_file(&path)?;
+            return Ok((Segment::create(directory, first_sequence)?, 0));
+        }
+
+        let mut reader = LogReader::new(BufReader::new(File::open(&path)?))?;
+        let mut entries = Vec::new();
+        let mut last_sequence = None;
+
+        loop {
+            let offset = reader.position();
+
+            match reader.next_record() {
+                Ok(Some((sequence, _))) => {
+                    last_sequence = Some(sequence);
+                    entries.extend_from_slice(&index_entry(sequence, offset));
+                }
+                Ok(None) => break,
+                Err(e)
+                    if e.kind() == ErrorKind::UnexpectedEof
+                        || e.kind() == ErrorKind::InvalidData =>
+                {
+                    println!("Invalid record at offset {} of {:?}: {}", offset, path, e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let file = open_for_append(&path)?;
+        let length = reader.position();
+        let truncated_bytes = file.metadata()?.len() - length;
+
+        if truncated_bytes > 0 {
+            println!(
+                "Truncating {} bytes from the tail of {:?}",
+                truncated_bytes, path
+            );
+            file.set_len(length)?;
+        }
+
+        let mut index = File::create(index_path(directory, first_sequence))?;
+        index.write_all(&entries)?;
+
+        Ok((
+            Segment {
+                first_sequence,
+                last_sequence,
+                file,
+                index,
+                length,
+            },
+            truncated_bytes,
+        ))
+    }
+
+    pub fn remove(directory: &Path, first_sequence: usize) -> IOResult<()> {
+        fs::remove_file(segment_path(directory, first_sequence))?;
+
+        match fs::remove_file(index_path(directory, first_sequence)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn append(&mut self, sequence: usize, command: &Command) -> IOResult<()> {
+        let record = encode_record(sequence, command);
+
+        self.file.write_all(&record)?;
+        self.index.write_all(&index_entry(sequence, self.length))?;
+        self.length += record.len() as u64;
+        self.last_sequence = Some(sequence);
+
+        Ok(())
+    }
+
+    pub fn first_sequence(&self) -> usize {
+        self.first_sequence
+    }
+
+    pub fn last_sequence(&self) -> Option<usize> {
+        self.last_sequence
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    // Flushes the segment and its index to stable storage, used when the segment is sealed
+    pub fn sync(&self) -> IOResult<()> {
+        self.index.sync_data()?;
+        self.file.sync_data()
+    }
+
+    /*
+     * Reader positioned at the first record of the segment with a sequence greater or equal than
+     * `sequence`.
+     */
+    pub fn reader_from(
+        directory: &Path,
+        first_sequence: usize,
+        sequence: usize,
+    ) -> IOResult<LogReader<BufReader<File>>> {
+        let file = File::open(segment_path(directory, first_sequence))?;
+        let offset = match Segment::find_offset(directory, first_sequence, sequence)? {
+            Some(offset) => offset,
+            None => file.metadata()?.len(),
+        };
+
+        LogReader::at(BufReader::new(file), offset)
+    }
+
+    // Binary search in the index for the first record with a sequence >= `sequence`
+    fn find_offset(
+        directory: &Path,
+        first_sequence: usize,
+        sequence: usize,
+    ) -> IOResult<Option<u64>> {
+        let mut index = match File::open(index_path(directory, first_sequence)) {
+            Ok(index) => index,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Segment::rebuild_index(directory, first_sequence)?;
+                File::open(index_path(directory, first_sequence))?
+            }
+            Err(e) => return Err(e),
+        };
+        let entries = index.metadata()?.len() / INDEX_ENTRY_LEN;
+        let mut entry = [0; INDEX_ENTRY_LEN as usize];
+        let (mut low, mut high) = (0, entries);
+
+        while low < high {
+            let middle = (low + high) / 2;
+            index.seek(SeekFrom::Start(middle * INDEX_ENTRY_LEN))?;
+            index.read_exact(&mut entry)?;
+
+            if (u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize) < sequence {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        if low == entries {
+            return Ok(None);
+        }
+
+        index.seek(SeekFrom::Start(low * INDEX_ENTRY_LEN))?;
+        index.read_exact(&mut entry)?;
+
+        Ok(Some(u64::from_le_bytes(entry[8..16].try_into().unwrap())))
+    }
+
+    /*
+     * Checks that the last entry of the index of a sealed segment points to the last record of the
+     * segment and rebuilds the index otherwise. The index is not flushed along with the records,
+     * so it can be missing entries after a crash.
+     */
+    pub fn verify_index(directory: &Path, first_sequence: usize) -> IOResult<()> {
+        let segment_length = fs::metadata(segment_path(directory, first_sequence))?.len();
+        let last_entry = match File::open(index_path(directory, first_sequence)) {
+            Ok(mut index) => {
+                let entries = index.metadata()?.len() / INDEX_ENTRY_LEN;
+                let mut entry = [0; INDEX_ENTRY_LEN as usize];
+
+                if entries > 0 {
+                    index.seek(SeekFrom::Start((entries - 1) * INDEX_ENTRY_LEN))?;
+                    index.read_exact(&mut entry)?;
+                    Some(u64::from_le_bytes(entry[8..16].try_into().unwrap()))
+                } else {
+                    None
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let valid = match last_entry {
+            None => segment_length == HEADER_LEN as u64,
+            Some(offset) => {
+                let file = File::open(segment_path(directory, first_sequence))?;
+                let mut reader = LogReader::at(BufReader::new(file), offset)?;
+
+                matches!(reader.next_record(), Ok(Some(_))) && reader.position() == segment_length
+            }
+        };
+
+        if !valid {
+            println!("Rebuilding index of segment {}", first_sequence);
+            Segment::rebuild_index(directory, first_sequence)?;
+        }
+
+        Ok(())
+    }
+
+    fn rebuild_index(directory: &Path, first_sequence: usize) -> IOResult<()> {
+        let path = segment_path(directory, first_sequence);
+        let mut reader = LogReader::new(BufReader::new(File::open(path)?))?;
+        let mut index = BufWriter::new(File::create(index_path(directory, first_sequence))?);
+
+        loop {
+            let offset = reader.position();
+
+            match reader.next_record()? {
+                Some((sequence, _)) => index.write_all(&index_entry(sequence, offset))?,
+                None => break,
+            }
+        }
+
+        index.flush()
+    }
+}
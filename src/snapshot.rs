@@ -0,0 +1,172 @@
+use crate::Entry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result as IOResult, Write};
+use std::path::{Path, PathBuf};
+
+// Snapshot files are laid out as:
+//
+//   | magic | format version (u8) | crc32 of the payload (u32 LE) | payload |
+//
+// where the payload is the bincode encoding of a `Snapshot`.
+const MAGIC: &[u8; 4] = b"RKVS";
+const FORMAT_VERSION: u8 = 1;
+const SNAPSHOT_INFIX: &str = ".snapshot.";
+
+/*
+ * Point-in-time copy of the KV map. It contains the effects of every command in the log up to
+ * (and including) `sequence`.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Snapshot {
+    pub sequence: usize,
+    #[serde(with = "bytes_keys")]
+    pub entries: BTreeMap<Vec<u8>, Entry>,
+}
+
+// `serde_bytes` for the keys of the map. The map is sent as a list of pairs because JSON only
+// allows strings as keys.
+mod bytes_keys {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer, V: Serialize>(
+        map: &BTreeMap<Vec<u8>, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter().map(|(key, value)| (Bytes::new(key), value)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Vec<u8>, V>, D::Error> {
+        Ok(Vec::<(ByteBuf, V)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value))
+            .collect())
+    }
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn parent_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Makes the creation, rename or removal of the file at `path` durable
+pub fn sync_parent_directory(path: &Path) -> IOResult<()> {
+    File::open(parent_directory(path))?.sync_all()
+}
+
+fn snapshot_filename(log_path: &str, sequence: usize) -> String {
+    format!("{log_path}{SNAPSHOT_INFIX}{sequence}")
+}
+
+/*
+ * Snapshots written for the given log, newest first.
+ */
+fn list(log_path: &str) -> IOResult<Vec<(usize, PathBuf)>> {
+    let log_path = Path::new(log_path);
+    let prefix = format!(
+        "{}{SNAPSHOT_INFIX}",
+        log_path.file_name().unwrap().to_str().unwrap()
+    );
+    let mut snapshots = Vec::new();
+
+    for entry in fs::read_dir(parent_directory(log_path))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(sequence) = name.to_str().and_then(|name| name.strip_prefix(&prefix)) else {
+            continue;
+        };
+
+        if let Ok(sequence) = sequence.parse::<usize>() {
+            snapshots.push((sequence, entry.path()));
+        }
+    }
+
+    snapshots.sort_by_key(|(sequence, _)| std::cmp::Reverse(*sequence));
+    Ok(snapshots)
+}
+
+fn read(path: &Path) -> IOResult<Snapshot> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let header_len = MAGIC.len() + 1 + 4;
+    if bytes.len() < header_len || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("not a snapshot file"));
+    }
+
+    if bytes[MAGIC.len()] != FORMAT_VERSION {
+        return Err(invalid_data("unsupported snapshot format version"));
+    }
+
+    let checksum = u32::from_le_bytes(bytes[MAGIC.len() + 1..header_len].try_into().unwrap());
+    let payload = &bytes[header_len..];
+
+    if crc32fast::hash(payload) != checksum {
+        return Err(invalid_data("snapshot checksum mismatch"));
+    }
+
+    bincode::deserialize(payload).map_err(|_| invalid_data("malformed snapshot"))
+}
+
+/*
+ * Writes the snapshot next to the log and removes the snapshots it supersedes. The snapshot is
+ * written to a temporary file that is renamed once it's on stable storage, so a crash never
+ * leaves a partial snapshot behind.
+ */
+pub fn write(log_path: &str, snapshot: &Snapshot) -> IOResult<()> {
+    let payload = bincode::serialize(snapshot).unwrap();
+    let filename = snapshot_filename(log_path, snapshot.sequence);
+    let tmp_filename = format!("{filename}.tmp");
+    let mut file = File::create(&tmp_filename)?;
+
+    file.write_all(MAGIC)?;
+    file.write_all(&[FORMAT_VERSION])?;
+    file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_filename, &filename)?;
+    sync_parent_directory(Path::new(&filename))?;
+
+    for (sequence, path) in list(log_path)? {
+        if sequence < snapshot.sequence {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Loads the newest snapshot of the log that can be read back. A snapshot that fails to load is
+ * skipped in favour of an older one.
+ */
+pub fn load_latest(log_path: &str) -> IOResult<Option<Snapshot>> {
+    for (_, path) in list(log_path)? {
+        match read(&path) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(e) => println!("Skipping snapshot {:?}: {}", path, e),
+        }
+    }
+
+    Ok(None)
+}
+
+// Sequence covered by the newest snapshot of the log, 0 if there are none
+pub fn latest_sequence(log_path: &str) -> IOResult<usize> {
+    Ok(list(log_path)?
+        .first()
+        .map(|(sequence, _)| *sequence)
+        .unwrap_or(0))
+}
@@ -0,0 +1,431 @@
+use crate::Entry;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result as IOResult, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Tables are immutable files sorted by key, laid out as:
+//
+//   | data block | ... | data block | index | bloom filter | footer |
+//
+// Each data block is the bincode encoding of a list of `Record`s. The index has the last key,
+// position and crc32 of every block, so a lookup reads at most one block. The footer is:
+//
+//   | index offset (u64 LE) | index length (u64 LE) | bloom length (u64 LE) |
+//   | crc32 of the index and the bloom filter (u32 LE) | magic |
+const MAGIC: &[u8; 4] = b"RKVT";
+const FOOTER_BYTES: u64 = 8 + 8 + 8 + 4 + 4;
+// Size after which a data block is closed
+const BLOCK_BYTES: usize = 4096;
+// A false positive rate of about 1% for 10 bits per key
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+
+// `None` is a tombstone: the key was deleted and older tables must not be looked at
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    entry: Option<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockHandle {
+    #[serde(with = "serde_bytes")]
+    last_key: Vec<u8>,
+    offset: u64,
+    length: u64,
+    checksum: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Index {
+    #[serde(with = "serde_bytes")]
+    first_key: Vec<u8>,
+    blocks: Vec<BlockHandle>,
+}
+
+// The two hashes every probe of the bloom filter is derived from. crc32 is used because, unlike
+// the hashers of the standard library, it's guaranteed not to change between releases.
+fn bloom_hashes(key: &[u8]) -> (u32, u32) {
+    let mut second = crc32fast::Hasher::new_with_initial(0x9e37_79b9);
+    second.update(key);
+
+    (crc32fast::hash(key), second.finalize() | 1)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn new(hashes: &[(u32, u32)]) -> BloomFilter {
+        let words = (hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(64).max(1);
+        let mut filter = BloomFilter {
+            bits: vec![0; words],
+        };
+
+        for hash in hashes {
+            for bit in filter.probes(*hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+
+        filter
+    }
+
+    fn probes(&self, (first, second): (u32, u32)) -> impl Iterator<Item = usize> {
+        let length = (self.bits.len() * 64) as u64;
+
+        (0..BLOOM_HASHES)
+            .map(move |i| ((first as u64 + i as u64 * second as u64) % length) as usize)
+    }
+
+    // `false` if the key is certainly not in the table
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(bloom_hashes(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+pub fn table_filename(directory: &Path, id: u64) -> PathBuf {
+    directory.join(format!("{id}.sst"))
+}
+
+/*
+ * Writes a table from entries added in ascending key order. The table can only be read once
+ * `finish` wrote its index and made it durable.
+ */
+pub struct TableWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    offset: u64,
+    block: Vec<Record>,
+    block_bytes: usize,
+    index: Index,
+    hashes: Vec<(u32, u32)>,
+}
+
+impl TableWriter {
+    pub fn create(directory: &Path, id: u64) -> IOResult<TableWriter> {
+        let path = table_filename(directory, id);
+
+        Ok(TableWriter {
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            offset: 0,
+            block: Vec::new(),
+            block_bytes: 0,
+            index: Index {
+                first_key: Vec::new(),
+                blocks: Vec::new(),
+            },
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: Vec<u8>, entry: Option<Entry>) -> IOResult<()> {
+        if self.hashes.is_empty() {
+            self.index.first_key = key.clone();
+        }
+
+        self.hashes.push(bloom_hashes(&key));
+        self.block_bytes += key.len() + entry.as_ref().map_or(0, |entry| entry.value.len()) + 32;
+        self.block.push(Record { key, entry });
+
+        if self.block_bytes >= BLOCK_BYTES {
+            self.write_block()?;
+        }
+
+        Ok(())
+    }
+
+    // Bytes written so far, used to split the output of a compaction
+    pub fn bytes(&self) -> u64 {
+        self.offset + self.block_bytes as u64
+    }
+
+    fn write_block(&mut self) -> IOResult<()> {
+        let Some(last) = self.block.last() else {
+            return Ok(());
+        };
+
+        let last_key = last.key.clone();
+        let payload = bincode::serialize(&self.block).unwrap();
+        self.file.write_all(&payload)?;
+
+        self.index.blocks.push(BlockHandle {
+            last_key,
+            offset: self.offset,
+            length: payload.len() as u64,
+            checksum: crc32fast::hash(&payload),
+        });
+        self.offset += payload.len() as u64;
+        self.block.clear();
+        self.block_bytes = 0;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> IOResult<SSTable> {
+        self.write_block()?;
+
+        let index = bincode::serialize(&self.index).unwrap();
+        let bloom = bincode::serialize(&BloomFilter::new(&self.hashes)).unwrap();
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&index);
+        checksum.update(&bloom);
+
+        self.file.write_all(&index)?;
+        self.file.write_all(&bloom)?;
+        self.file.write_all(&self.offset.to_le_bytes())?;
+        self.file.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.file.write_all(&(bloom.len() as u64).to_le_bytes())?;
+        self.file.write_all(&checksum.finalize().to_le_bytes())?;
+        self.file.write_all(MAGIC)?;
+        self.file.into_inner()?.sync_all()?;
+
+        SSTable::open(&self.path)
+    }
+}
+
+/*
+ * Table read back from disk. Its index and bloom filter are kept in memory, the blocks are read
+ * when they are needed.
+ */
+#[derive(Debug)]
+pub struct SSTable {
+    path: PathBuf,
+    file: File,
+    index: Index,
+    bloom: BloomFilter,
+    bytes: u64,
+}
+
+impl SSTable {
+    pub fn open(path: &Path) -> IOResult<SSTable> {
+        let file = File::open(path)?;
+        let bytes = file.metadata()?.len();
+
+        if bytes < FOOTER_BYTES {
+            return Err(invalid_data("not a table file"));
+        }
+
+        let mut footer = [0; FOOTER_BYTES as usize];
+        file.read_exact_at(&mut footer, bytes - FOOTER_BYTES)?;
+
+        if &footer[28..] != MAGIC {
+            return Err(invalid_data("not a table file"));
+        }
+
+        let field = |at: usize| u64::from_le_bytes(footer[at..at + 8].try_into().unwrap());
+        let (index_offset, index_length, bloom_length) = (field(0), field(8), field(16));
+        let checksum = u32::from_le_bytes(footer[24..28].try_into().unwrap());
+
+        if index_offset + index_length + bloom_length + FOOTER_BYTES != bytes {
+            return Err(invalid_data("table footer doesn't match its length"));
+        }
+
+        let mut metadata = vec![0; (index_length + bloom_length) as usize];
+        file.read_exact_at(&mut metadata, index_offset)?;
+
+        if crc32fast::hash(&metadata) != checksum {
+            return Err(invalid_data("table index checksum mismatch"));
+        }
+
+        let (index, bloom) = metadata.split_at(index_length as usize);
+
+        Ok(SSTable {
+            path: path.to_path_buf(),
+            file,
+            index: bincode::deserialize(index).map_err(|_| invalid_data("malformed index"))?,
+            bloom: bincode::deserialize(bloom).map_err(|_| invalid_data("malformed bloom"))?,
+            bytes,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn first_key(&self) -> &[u8] {
+        &self.index.first_key
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        self.index
+            .blocks
+            .last()
+            .map_or(&self.index.first_key, |block| &block.last_key)
+    }
+
+    // Whether the table might have keys from `first` to `last`, both included
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        !self.index.blocks.is_empty() && self.first_key() <= last && self.last_key() >= first
+    }
+
+    fn read_block(&self, block: usize) -> IOResult<Vec<Record>> {
+        let handle = &self.index.blocks[block];
+        let mut payload = vec![0; handle.length as usize];
+        self.file.read_exact_at(&mut payload, handle.offset)?;
+
+        if crc32fast::hash(&payload) != handle.checksum {
+            return Err(invalid_data("table block checksum mismatch"));
+        }
+
+        bincode::deserialize(&payload).map_err(|_| invalid_data("malformed table block"))
+    }
+
+    // First block that can hold `key`
+    fn block_of(&self, key: &[u8]) -> usize {
+        self.index
+            .blocks
+            .partition_point(|block| block.last_key.as_slice() < key)
+    }
+
+    /*
+     * `None` if the table knows nothing about the key, otherwise its entry in the table, which is
+     * `None` too if the key was deleted.
+     */
+    pub fn get(&self, key: &[u8]) -> IOResult<Option<Option<Entry>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+
+        let block = self.block_of(key);
+        if block == self.index.blocks.len() {
+            return Ok(None);
+        }
+
+        let records = self.read_block(block)?;
+        Ok(records
+            .binary_search_by(|record| record.key.as_slice().cmp(key))
+            .ok()
+            .map(|i| records[i].entry.clone()))
+    }
+
+    // Records of the table from `from` onwards, in key order. Blocks are read as the iterator
+    // gets to them.
+    pub fn iter(self: &Arc<Self>, from: Bound<&[u8]>) -> TableIterator {
+        let block = match from {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_of(key),
+            Bound::Unbounded => 0,
+        };
+
+        TableIterator {
+            table: self.clone(),
+            block,
+            records: Vec::new().into_iter(),
+            from: match from {
+                Bound::Included(key) => Bound::Included(key.to_vec()),
+                Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        }
+    }
+}
+
+pub struct TableIterator {
+    table: Arc<SSTable>,
+    block: usize,
+    records: std::vec::IntoIter<Record>,
+    from: Bound<Vec<u8>>,
+}
+
+impl Iterator for TableIterator {
+    type Item = (Vec<u8>, Option<Entry>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                let after_start = match &self.from {
+                    Bound::Included(from) => &record.key >= from,
+                    Bound::Excluded(from) => &record.key > from,
+                    Bound::Unbounded => true,
+                };
+
+                if after_start {
+                    // Every following record is after the start too
+                    self.from = Bound::Unbounded;
+                    return Some((record.key, record.entry));
+                }
+
+                continue;
+            }
+
+            if self.block >= self.table.index.blocks.len() {
+                return None;
+            }
+
+            // Tables are immutable, a block that can't be read is a corrupt table
+            self.records = self.table.read_block(self.block).unwrap().into_iter();
+            self.block += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TableWriter;
+    use crate::Entry;
+    use std::fs;
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    fn entry(version: usize) -> Entry {
+        Entry {
+            value: vec![b'v'; 100],
+            version,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_table_lookups_and_iteration() {
+        let directory = std::env::temp_dir().join(format!("rustkv-sstable-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut writer = TableWriter::create(&directory, 1).unwrap();
+        for i in 0..1000usize {
+            let entry = if i % 10 == 0 { None } else { Some(entry(i)) };
+            writer
+                .add(format!("key-{:04}", i).into_bytes(), entry)
+                .unwrap();
+        }
+        let table = Arc::new(writer.finish().unwrap());
+
+        assert!(table.index.blocks.len() > 1);
+        assert_eq!(table.first_key(), b"key-0000");
+        assert_eq!(table.last_key(), b"key-0999");
+        assert_eq!(table.get(b"key-0500").unwrap(), Some(None));
+        assert_eq!(table.get(b"key-0501").unwrap(), Some(Some(entry(501))));
+        assert_eq!(table.get(b"key-5000").unwrap(), None);
+        assert_eq!(table.get(b"zzz").unwrap(), None);
+
+        let keys: Vec<Vec<u8>> = table
+            .iter(Bound::Excluded(b"key-0997"))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"key-0998".to_vec(), b"key-0999".to_vec()]);
+        assert_eq!(table.iter(Bound::Unbounded).count(), 1000);
+
+        // Keys that were never added are mostly rejected by the bloom filter alone
+        let false_positives = (1000..11000)
+            .filter(|i| table.bloom.may_contain(format!("key-{:04}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 500);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::snapshot::Snapshot;
+use crate::Entry;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/*
+ * Where a node keeps the entries of its keys. Engines store entries as they are given, expired
+ * ones included: hiding them and sweeping them away is up to the KV. Entries are returned by value
+ * because an engine doesn't have to keep them in memory.
+ */
+pub trait StorageEngine: Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<Entry>;
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry);
+
+    fn delete(&mut self, key: &[u8]);
+
+    // Entries from `from` to `to` in key order. An empty range has no entries.
+    fn scan<'a>(
+        &'a self,
+        from: Bound<&[u8]>,
+        to: Bound<&[u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Entry)> + 'a>;
+
+    // Replaces every entry with the ones of the snapshot
+    fn restore(&mut self, snapshot: Snapshot);
+
+    fn snapshot(&self, sequence: usize) -> Snapshot {
+        Snapshot {
+            sequence,
+            entries: self.scan(Bound::Unbounded, Bound::Unbounded).collect(),
+        }
+    }
+
+    // Keys whose entries expired by `now`
+    fn expired(&mut self, now: u64) -> Vec<Vec<u8>> {
+        self.scan(Bound::Unbounded, Bound::Unbounded)
+            .filter(|(_, entry)| entry.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    // Called once the command at `sequence` has been applied
+    fn applied(&mut self, _sequence: usize) {}
+
+    /*
+     * Makes the effects of every applied command survive a restart, for the engines that keep
+     * their entries on disk.
+     */
+    fn flush(&mut self) {}
+
+    /*
+     * Sequence of the last command whose effects survive a restart, so the log only has to be
+     * replayed after it. `None` if the engine doesn't keep its entries and has to be restored from
+     * a snapshot instead.
+     */
+    fn durable_sequence(&self) -> Option<usize> {
+        None
+    }
+}
+
+// Whether there is no key from `from` to `to`, which `BTreeMap::range` panics on
+pub fn is_empty_range(from: Bound<&[u8]>, to: Bound<&[u8]>) -> bool {
+    match (from, to) {
+        (Bound::Included(from), Bound::Included(to)) => from > to,
+        (Bound::Included(from) | Bound::Excluded(from), Bound::Excluded(to))
+        | (Bound::Excluded(from), Bound::Included(to)) => from >= to,
+        _ => false,
+    }
+}
+
+// Every entry in a map, lost when the node stops unless it's in the log or a snapshot
+#[derive(Default)]
+pub struct MemoryEngine {
+    map: BTreeMap<Vec<u8>, Entry>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::default()
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.map.get(key).cloned()
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: Entry) {
+        self.map.insert(key, entry);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.map.remove(key);
+    }
+
+    fn scan<'a>(
+        &'a self,
+        from: Bound<&[u8]>,
+        to: Bound<&[u8]>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Entry)> + 'a> {
+        if is_empty_range(from, to) {
+            return Box::new(std::iter::empty());
+        }
+
+        Box::new(
+            self.map
+                .range::<[u8], _>((from, to))
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+        )
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.map = snapshot.entries;
+    }
+
+    fn snapshot(&self, sequence: usize) -> Snapshot {
+        Snapshot {
+            sequence,
+            entries: self.map.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryEngine, StorageEngine};
+    use crate::snapshot::Snapshot;
+    use crate::Entry;
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    fn entry(version: usize) -> Entry {
+        Entry {
+            value: version.to_string().into_bytes(),
+            version,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_memory_engine_scans_in_key_order() {
+        let mut engine = MemoryEngine::new();
+        engine.put(b"c".to_vec(), entry(1));
+        engine.put(b"a".to_vec(), entry(2));
+        engine.put(b"b".to_vec(), entry(3));
+        engine.delete(b"c");
+
+        let keys: Vec<Vec<u8>> = engine
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+
+        assert_eq!(
+            engine
+                .scan(Bound::Excluded(b"b"), Bound::Excluded(b"a"))
+                .count(),
+            0
+        );
+
+        engine.restore(Snapshot {
+            sequence: 4,
+            entries: BTreeMap::from([(b"d".to_vec(), entry(4))]),
+        });
+        assert_eq!(engine.get(b"a"), None);
+        assert_eq!(engine.snapshot(4).entries.len(), 1);
+    }
+}